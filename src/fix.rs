@@ -0,0 +1,1729 @@
+use anyhow::{bail, Context};
+use itertools::Itertools;
+use log::debug;
+use serde::Serialize;
+use std::{
+    fs,
+    io::stdin,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use terminal_size::{terminal_size, Width};
+
+use crate::{
+    metadata::{self, Disposition, FileMetadata, OtherMetadata, VideoMetadata},
+    paths::Sidecar,
+    report::serialize_optional_path,
+    validation::{
+        self, AudioValidation, ComponentValidation, FormatValidation, Selection, VideoValidation,
+    },
+    DefaultAudio, Formats, Target, VideoQuality,
+};
+
+/// How far (in minutes) the duration of a fix may drift from its source; remuxing and
+/// reencoding can shift the end by a few frames, but anything more means a truncated encode
+const MAX_DURATION_DIFFERENCE: f64 = 1.0 / 60.0;
+
+/// Everything needed to fix a single file, worked out before ffmpeg is started
+#[derive(Debug)]
+pub(crate) struct FixPlan {
+    pub(crate) out_path: PathBuf,
+    pub(crate) streams: Vec<StreamPlan>,
+    command: Command,
+}
+
+/// What the fix will do with a single input stream
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct StreamPlan {
+    pub(crate) index: i64,
+    pub(crate) kind: StreamKind,
+    pub(crate) codec: String,
+    pub(crate) action: StreamAction,
+    /// A new stream made from the input stream, in addition to whatever happens to that stream
+    pub(crate) added: bool,
+    /// The sidecar file the stream comes from, `None` for streams of the input file
+    #[serde(serialize_with = "serialize_optional_path")]
+    pub(crate) sidecar: Option<PathBuf>,
+    /// Per stream ffmpeg options (e.g. `pix_fmt`), given the output stream as specifier
+    #[serde(skip)]
+    options: Vec<(&'static str, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum StreamKind {
+    Video,
+    Audio,
+    Subtitle,
+    CoverArt,
+    Attachment,
+    Data,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "type", content = "codec")]
+pub(crate) enum StreamAction {
+    Copy,
+    Transcode(String),
+    Drop,
+}
+
+impl FixPlan {
+    pub(crate) fn new(
+        in_path: &Path,
+        metadata: &FileMetadata,
+        val: &FormatValidation,
+        sidecars: &[Sidecar],
+        target: &Target,
+    ) -> anyhow::Result<Self> {
+        let mut out_container = output_container(in_path, val, target);
+        let mut streams = plan_streams(metadata, val, sidecars, &out_container, target);
+        if !streams.iter().all(|s| container_holds(&out_container, s)) {
+            // e.g. a webm file that needs its audio transcoded to something other than Opus
+            out_container = target.default.container.to_lowercase();
+            streams = plan_streams(metadata, val, sidecars, &out_container, target);
+        }
+        let out_path = in_path.with_extension(format!("fixed.{}", out_container));
+
+        // TODO: could let ffmepg prompt for this instead
+        if out_path.exists() {
+            bail!("fix target {} already exists", out_path.display());
+        }
+
+        let kept = streams
+            .iter()
+            .filter(|s| s.action != StreamAction::Drop)
+            .collect_vec();
+
+        let mut cmd = Command::new("ffmpeg");
+        cmd.arg("-loglevel")
+            .arg("warning")
+            .arg("-stats")
+            .arg("-i")
+            .arg(in_path);
+        // each sidecar is an input of its own, numbered after the file itself
+        for path in kept.iter().filter_map(|s| s.sidecar.as_ref()) {
+            cmd.arg("-i").arg(path);
+        }
+
+        // map streams explicitly so every track is kept rather than just the ones ffmpeg
+        // would select by default, and chapters and metadata come along with them
+        let mut sidecar_input = 0;
+        for stream in &kept {
+            let input = if stream.sidecar.is_some() {
+                sidecar_input += 1;
+                sidecar_input
+            } else {
+                0
+            };
+            cmd.arg("-map").arg(format!("{}:{}", input, stream.index));
+        }
+        cmd.arg("-map_chapters")
+            .arg("0")
+            .arg("-map_metadata")
+            .arg("0");
+
+        for (i, stream) in kept.iter().enumerate() {
+            cmd.arg(format!("-c:{}", i)).arg(stream.action.codec());
+            for (option, value) in &stream.options {
+                cmd.arg(format!("-{}:{}", option, i)).arg(value);
+            }
+            if matches!(stream.action, StreamAction::Transcode(_)) {
+                // stream tags are copied along, but mkvmerge's bitrate statistics no longer
+                // describe a transcoded stream
+                cmd.arg(format!("-metadata:s:{}", i)).arg("BPS=");
+            }
+        }
+
+        cmd.args(&target.encode.extra_args).arg(&out_path);
+
+        Ok(FixPlan {
+            out_path,
+            streams,
+            command: cmd,
+        })
+    }
+
+    /// Whether the fix only moves the streams into a new container without touching them
+    pub(crate) fn is_remux(&self) -> bool {
+        self.streams
+            .iter()
+            .all(|s| s.action == StreamAction::Copy && !s.added)
+    }
+
+    /// The ffmpeg command line, quoted so it can be pasted into a shell
+    pub(crate) fn command_line(&self) -> String {
+        std::iter::once(self.command.get_program())
+            .chain(self.command.get_args())
+            .map(|arg| {
+                let arg = arg.to_string_lossy();
+                shlex::try_quote(&arg)
+                    .map(|quoted| quoted.into_owned())
+                    .unwrap_or_else(|_| arg.into_owned())
+            })
+            .join(" ")
+    }
+}
+
+/// Decide what happens to every stream of the file, and to its sidecars, when writing it
+/// into `out_container`
+fn plan_streams(
+    metadata: &FileMetadata,
+    val: &FormatValidation,
+    sidecars: &[Sidecar],
+    out_container: &str,
+    target: &Target,
+) -> Vec<StreamPlan> {
+    let default = &target.default;
+    let mut streams = Vec::new();
+
+    for (stream, v) in metadata.video.iter().zip(&val.video) {
+        let range_fix = dynamic_range_fix(stream, v, target);
+        let codec_tag = output_codec_tag(stream, out_container, target);
+        let action = if range_fix.to_sdr()
+            || !v.picture_components().all(|c| c.okay)
+            || codec_tag == TagFix::Reencode
+        {
+            StreamAction::Transcode(default.video.clone())
+        } else {
+            StreamAction::Copy
+        };
+        let mut options = Vec::new();
+        if action != StreamAction::Copy {
+            // set even when the source's pixel format is accepted, as encoders otherwise keep
+            // it (e.g. 10-bit) and the profile picked below may not allow it
+            options.push(("pix_fmt", default.pix_fmt.clone()));
+            options.extend(video_encode_options(target));
+        }
+        if let (Some(max), Some(false)) = (
+            target.format_spec.max_video_bitrate,
+            v.bit_rate.as_ref().map(|b| b.okay),
+        ) {
+            // cap the peak rate, leaving the average to the configured quality
+            options.push(("maxrate", max.to_string()));
+            options.push(("bufsize", (max * 2).to_string()));
+        }
+        if range_fix == (RangeFix::StripDolbyVision { tonemap: false })
+            && action == StreamAction::Copy
+        {
+            // drop the Dolby Vision RPU and keep the compatible base layer as is
+            options.push(("bsf", "dovi_rm".to_string()));
+        }
+        if let (TagFix::Retag(tag), StreamAction::Copy) = (&codec_tag, &action) {
+            options.push(("tag", tag.clone()));
+        }
+        if range_fix.to_sdr() {
+            // tag the output as SDR rather than relying on the encoder to pick it up
+            // from the filtered frames
+            options.extend(
+                ["color_primaries", "color_trc", "colorspace"]
+                    .map(|option| (option, "bt709".to_string())),
+            );
+        }
+        let filters = video_filters(stream, v, range_fix, target);
+        if !filters.is_empty() {
+            options.push(("filter", filters.join(",")));
+        }
+        streams.push(StreamPlan {
+            index: stream.index,
+            kind: StreamKind::Video,
+            codec: stream.codec.clone(),
+            action,
+            added: false,
+            sidecar: None,
+            options,
+        });
+    }
+
+    // with the add-compat strategy incompatible audio is always kept as is, and
+    // compatible copies are added after every other stream if the file doesn't already
+    // have one; streams the target's audio selection doesn't check are only ever copied
+    // or dropped
+    let keep_incompatible = val.compatible_audio.is_some();
+    let add_compat = val.compatible_audio.as_ref().is_some_and(|c| !c.okay);
+    let mut added = Vec::new();
+    for (stream, a) in metadata.audio.iter().zip(&val.audio) {
+        let incompatible = a.selection == Selection::Checked && !a.components().all(|c| c.okay);
+        let mut plan = StreamPlan {
+            index: stream.index,
+            kind: StreamKind::Audio,
+            codec: stream.codec.clone(),
+            action: StreamAction::Copy,
+            added: false,
+            sidecar: None,
+            options: Vec::new(),
+        };
+        if add_compat && incompatible {
+            added.push(StreamPlan {
+                action: StreamAction::Transcode(default.audio.clone()),
+                added: true,
+                options: audio_encode_options(a, target),
+                ..plan.clone()
+            });
+        } else if incompatible && !keep_incompatible {
+            plan.action = StreamAction::Transcode(default.audio.clone());
+            plan.options = audio_encode_options(a, target);
+        } else if a.selection == Selection::Dropped {
+            plan.action = StreamAction::Drop;
+        }
+        streams.push(plan);
+    }
+
+    // subtitles can't be transcoded between image and text formats, so the only fix
+    // for a rejected subtitle stream is to leave it out
+    for (stream, s) in metadata.subtitle.iter().zip(&val.subtitle) {
+        let action = if s.codec.okay {
+            subtitle_action(out_container, &stream.codec)
+        } else {
+            StreamAction::Drop
+        };
+        let mut options = Vec::new();
+        if s.forced.as_ref().is_some_and(|c| !c.okay) {
+            let disposition = Disposition {
+                forced: true,
+                ..stream.disposition.clone()
+            };
+            options.push(("disposition", disposition_flags(&disposition)));
+        }
+        streams.push(StreamPlan {
+            index: stream.index,
+            kind: StreamKind::Subtitle,
+            codec: stream.codec.clone(),
+            action,
+            added: false,
+            sidecar: None,
+            options,
+        });
+    }
+
+    for stream in &metadata.other {
+        let kind = other_kind(stream);
+        streams.push(StreamPlan {
+            index: stream.index,
+            kind,
+            codec: stream.codec.clone().unwrap_or_default(),
+            action: other_action(out_container, kind, val.container.okay),
+            added: false,
+            sidecar: None,
+            options: Vec::new(),
+        });
+    }
+
+    streams.sort_by_key(|s| s.index);
+    streams.extend(added);
+    set_default_audio(&mut streams, metadata, val, target);
+    streams.extend(
+        sidecars
+            .iter()
+            .map(|sidecar| sidecar_plan(sidecar, out_container, target)),
+    );
+    streams
+}
+
+/// Add a sidecar subtitle file as a new stream, tagged with the language and flags from its
+/// name; sidecars in a format the target rejects are left out like rejected subtitle streams
+fn sidecar_plan(sidecar: &Sidecar, out_container: &str, target: &Target) -> StreamPlan {
+    let action = if validation::is_allowed(&target.format_spec.subtitle, &sidecar.codec) {
+        subtitle_action(out_container, &sidecar.codec)
+    } else {
+        StreamAction::Drop
+    };
+    let mut options = Vec::new();
+    if let Some(language) = &sidecar.language {
+        options.push(("metadata:s", format!("language={}", language)));
+    }
+    let disposition = Disposition {
+        forced: sidecar.forced,
+        hearing_impaired: sidecar.hearing_impaired,
+        ..Disposition::default()
+    };
+    if disposition != Disposition::default() {
+        options.push(("disposition", disposition_flags(&disposition)));
+    }
+
+    StreamPlan {
+        index: 0,
+        kind: StreamKind::Subtitle,
+        codec: sidecar.codec.clone(),
+        action,
+        added: false,
+        sidecar: Some(sidecar.path.clone()),
+        options,
+    }
+}
+
+/// Flag a single output audio stream as the default, preferring one in the target's most
+/// preferred language that the target can play, and move it first if the target requires
+/// that; only done when the default audio rule failed or compatible streams were added
+fn set_default_audio(
+    streams: &mut Vec<StreamPlan>,
+    metadata: &FileMetadata,
+    val: &FormatValidation,
+    target: &Target,
+) {
+    let default_failed = val.default_audio.as_ref().is_some_and(|c| !c.okay);
+    if !default_failed && !streams.iter().any(|s| s.added) {
+        return;
+    }
+    let languages = target.format_spec.languages.as_ref();
+    let preferred = languages.and_then(|spec| validation::preferred_language(metadata, spec));
+    let is_audio = |s: &StreamPlan| s.kind == StreamKind::Audio && s.action != StreamAction::Drop;
+    let source = |s: &StreamPlan| {
+        metadata
+            .audio
+            .iter()
+            .zip(&val.audio)
+            .find(|(a, _)| a.index == s.index)
+    };
+
+    let chosen = streams
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| is_audio(s))
+        .min_by_key(|(_, s)| match source(s) {
+            Some((stream, a)) => {
+                let in_language = preferred.is_some() && stream.language.as_deref() == preferred;
+                // a transcoded stream always comes out compatible
+                let compatible = s.action != StreamAction::Copy || a.components().all(|c| c.okay);
+                (!in_language, !compatible)
+            }
+            None => (true, true),
+        })
+        .map(|(i, _)| i);
+    let Some(chosen) = chosen else {
+        return;
+    };
+
+    for (i, plan) in streams.iter_mut().enumerate() {
+        if !is_audio(plan) {
+            continue;
+        }
+        let disposition = Disposition {
+            default: i == chosen,
+            ..source(plan)
+                .map(|(stream, _)| stream.disposition.clone())
+                .unwrap_or_default()
+        };
+        plan.options
+            .push(("disposition", disposition_flags(&disposition)));
+    }
+
+    if languages.is_some_and(|spec| spec.default_audio == DefaultAudio::PreferredFirst) {
+        if let Some(first) = streams.iter().position(is_audio) {
+            let plan = streams.remove(chosen);
+            streams.insert(first, plan);
+        }
+    }
+}
+
+/// The value of ffmpeg's `-disposition` option that sets exactly these flags, "0" clearing
+/// them all
+fn disposition_flags(disposition: &Disposition) -> String {
+    let flags = [
+        ("default", disposition.default),
+        ("forced", disposition.forced),
+        ("original", disposition.original),
+        ("dub", disposition.dub),
+        ("comment", disposition.comment),
+        ("hearing_impaired", disposition.hearing_impaired),
+        ("visual_impaired", disposition.visual_impaired),
+    ]
+    .into_iter()
+    .filter(|(_, set)| *set)
+    .map(|(flag, _)| flag)
+    .join("+");
+    if flags.is_empty() {
+        "0".to_string()
+    } else {
+        flags
+    }
+}
+
+/// Keep the source container when the target accepts it, otherwise switch to the target's
+/// preferred one
+fn output_container(in_path: &Path, val: &FormatValidation, target: &Target) -> String {
+    in_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .filter(|_| val.container.okay)
+        .unwrap_or(&target.default.container)
+        .to_lowercase()
+}
+
+/// Codecs the webm muxer accepts, unlike Matroska which holds anything
+const WEBM_CODECS: [&str; 6] = ["vp8", "vp9", "av1", "opus", "vorbis", "webvtt"];
+
+/// Whether the muxer for `container` can hold the stream as planned
+fn container_holds(container: &str, stream: &StreamPlan) -> bool {
+    let codec = match &stream.action {
+        StreamAction::Copy => &stream.codec,
+        StreamAction::Transcode(codec) => codec,
+        StreamAction::Drop => return true,
+    };
+    container != "webm" || WEBM_CODECS.contains(&codec.as_str())
+}
+
+const TEXT_SUBTITLE_CODECS: [&str; 6] = ["subrip", "ass", "ssa", "webvtt", "mov_text", "text"];
+
+/// How an acceptable subtitle stream can be carried into `container`; MP4 style containers
+/// only hold `mov_text` while Matroska can hold anything except `mov_text`
+fn subtitle_action(container: &str, codec: &str) -> StreamAction {
+    let is_text = TEXT_SUBTITLE_CODECS.contains(&codec);
+    match container {
+        "mp4" | "m4v" | "mov" if codec == "mov_text" => StreamAction::Copy,
+        "mp4" | "m4v" | "mov" if is_text => StreamAction::Transcode("mov_text".to_string()),
+        "mp4" | "m4v" | "mov" => StreamAction::Drop,
+        "mkv" if codec == "mov_text" => StreamAction::Transcode("subrip".to_string()),
+        _ => StreamAction::Copy,
+    }
+}
+
+fn video_encode_options(target: &Target) -> Vec<(&'static str, String)> {
+    let encode = &target.encode;
+    let mut options = Vec::new();
+    match &encode.video_quality {
+        Some(VideoQuality::Crf(crf)) => options.push(("crf", crf.to_string())),
+        Some(VideoQuality::Bitrate(bitrate)) => options.push(("b", bitrate.clone())),
+        None => {}
+    }
+    // without explicit settings, keep the new stream within the target's own profile rules
+    let profile_spec = target.format_spec.video_profiles.get(&target.default.video);
+    let profile = encode.profile.clone().or_else(|| {
+        profile_spec.and_then(|spec| match &spec.profile {
+            Formats::Allow(profiles) => profiles.last().map(|p| encoder_profile(p)),
+            Formats::Reject(_) => None,
+        })
+    });
+    let level = encode.level.clone().or_else(|| {
+        profile_spec
+            .and_then(|spec| spec.max_level)
+            .map(|l| l.to_string())
+    });
+    let named = [
+        ("preset", &encode.preset),
+        ("tune", &encode.tune),
+        ("profile", &profile),
+        ("level", &level),
+    ];
+    options.extend(
+        named
+            .into_iter()
+            .filter_map(|(option, value)| value.as_ref().map(|v| (option, v.clone()))),
+    );
+    options
+}
+
+fn audio_encode_options(a: &AudioValidation, target: &Target) -> Vec<(&'static str, String)> {
+    let mut options = Vec::new();
+    if let Some(bitrate) = &target.encode.audio_bitrate {
+        options.push(("b", bitrate.clone()));
+    }
+    if let (Some(max), Some(false), None) = (
+        target.format_spec.max_audio_channels,
+        a.channels.as_ref().map(|c| c.okay),
+        &target.encode.downmix_filter,
+    ) {
+        options.push(("ac", max.to_string()));
+    }
+    let filters = audio_filters(a, target);
+    if !filters.is_empty() {
+        options.push(("filter", filters.join(",")));
+    }
+    options
+}
+
+/// Filters that bring an audio stream within the target's channel and sample rate rules
+fn audio_filters(a: &AudioValidation, target: &Target) -> Vec<String> {
+    let spec = &target.format_spec;
+    let failed = |c: &Option<ComponentValidation>| c.as_ref().is_some_and(|c| !c.okay);
+    let mut filters = Vec::new();
+
+    if failed(&a.channels) {
+        if let Some(downmix) = &target.encode.downmix_filter {
+            filters.push(downmix.clone());
+        }
+    }
+
+    // aformat lets ffmpeg convert to whichever allowed format is closest
+    let mut formats = Vec::new();
+    if let (true, Some(Formats::Allow(rates))) = (failed(&a.sample_rate), &spec.audio_sample_rate) {
+        formats.push(format!("sample_rates={}", rates.join("|")));
+    } else if failed(&a.sample_rate) {
+        filters.push("aresample=48000".to_string());
+    }
+    if let (true, Some(Formats::Allow(layouts))) =
+        (failed(&a.channel_layout), &spec.audio_channel_layout)
+    {
+        formats.push(format!("channel_layouts={}", layouts.join("|")));
+    }
+    if !formats.is_empty() {
+        filters.push(format!("aformat={}", formats.join(":")));
+    }
+
+    filters
+}
+
+/// Encoders take lowercase profile names without spaces, e.g. ffprobe's "Main 10" is "main10"
+fn encoder_profile(profile: &str) -> String {
+    profile.to_lowercase().replace(' ', "")
+}
+
+/// How a video stream's codec tag is brought in line with the target
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TagFix {
+    Keep,
+    /// Copy the stream with a different tag
+    Retag(String),
+    /// No tag the target accepts fits the codec
+    Reencode,
+}
+
+fn output_codec_tag(stream: &VideoMetadata, out_container: &str, target: &Target) -> TagFix {
+    // only MP4 style containers have codec tags
+    let rule = match &target.format_spec.video_codec_tag {
+        Some(rule) if matches!(out_container, "mp4" | "m4v" | "mov") => rule,
+        _ => return TagFix::Keep,
+    };
+    // a stream coming from another container gets ffmpeg's default tag, e.g. hev1 for hevc,
+    // so that has to be checked as well as an existing tag
+    if stream
+        .codec_tag
+        .as_ref()
+        .is_some_and(|tag| validation::is_allowed(rule, tag))
+    {
+        return TagFix::Keep;
+    }
+    let candidates: &[&str] = match stream.codec.as_str() {
+        "hevc" => &["hvc1", "hev1"],
+        "h264" => &["avc1", "avc3"],
+        _ => &[],
+    };
+    match candidates
+        .iter()
+        .find(|tag| validation::is_allowed(rule, tag))
+    {
+        Some(tag) => TagFix::Retag(tag.to_string()),
+        None => TagFix::Reencode,
+    }
+}
+
+/// How a video stream's dynamic range is brought in line with the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeFix {
+    Keep,
+    /// Tonemap HDR10 or HLG to SDR
+    Tonemap,
+    /// Remove Dolby Vision from a profile 7 or 8 stream, leaving its base layer, which may
+    /// need tonemapping in turn
+    StripDolbyVision {
+        tonemap: bool,
+    },
+    /// Apply the Dolby Vision reshaping and tonemap to SDR, for streams without a usable base
+    /// layer such as profile 5
+    Reshape,
+}
+
+impl RangeFix {
+    /// Whether the stream is converted to SDR, which means transcoding it
+    fn to_sdr(self) -> bool {
+        matches!(
+            self,
+            RangeFix::Tonemap | RangeFix::Reshape | RangeFix::StripDolbyVision { tonemap: true }
+        )
+    }
+}
+
+fn dynamic_range_fix(stream: &VideoMetadata, v: &VideoValidation, target: &Target) -> RangeFix {
+    if v.dynamic_range_okay() {
+        return RangeFix::Keep;
+    }
+    match &stream.dolby_vision {
+        Some(dv) => match dv.base_layer {
+            Some(base) if matches!(dv.profile, 7 | 8) => RangeFix::StripDolbyVision {
+                tonemap: !validation::hdr_allowed(&target.format_spec, base),
+            },
+            _ => RangeFix::Reshape,
+        },
+        None => RangeFix::Tonemap,
+    }
+}
+
+/// Filters that bring a video stream within the target's limits and dynamic range rules
+fn video_filters(
+    stream: &VideoMetadata,
+    v: &VideoValidation,
+    range_fix: RangeFix,
+    target: &Target,
+) -> Vec<String> {
+    let spec = &target.format_spec;
+    let mut filters = Vec::new();
+
+    // fields have to be combined before anything else touches the picture
+    if v.interlaced.as_ref().is_some_and(|c| !c.okay) {
+        filters.push(
+            target
+                .encode
+                .deinterlace_filter
+                .clone()
+                // one frame per pair of fields, keeping the frame rate the limits were
+                // checked against (bwdif's default doubles it)
+                .unwrap_or_else(|| "bwdif=mode=send_frame".to_string()),
+        );
+    }
+
+    // dropping frames first means fewer of them need scaling
+    if let (Some(max), Some(rate), Some(false)) = (
+        spec.max_frame_rate,
+        stream.frame_rate,
+        v.frame_rate.as_ref().map(|c| c.okay),
+    ) {
+        filters.push(format!("fps={}", reduced_frame_rate(rate, max)));
+    }
+
+    if let (Some(width), Some(height), Some(false)) = (
+        stream.width,
+        stream.height,
+        v.resolution.as_ref().map(|c| c.okay),
+    ) {
+        let (width, height) = scaled_size(width, height, spec.max_width, spec.max_height);
+        let mut scale = format!("scale={}:{}", width, height);
+        if let Some(scaler) = &target.encode.scaler {
+            scale.push_str(&format!(":flags={}", scaler));
+        }
+        filters.push(scale);
+    }
+
+    // tonemapping is by far the slowest step, so it runs on as few pixels as possible
+    match range_fix {
+        RangeFix::Tonemap | RangeFix::StripDolbyVision { tonemap: true } => {
+            filters.push(tonemap_filter(target))
+        }
+        RangeFix::Reshape => filters.push(reshape_filter(target)),
+        RangeFix::Keep | RangeFix::StripDolbyVision { tonemap: false } => {}
+    }
+
+    filters
+}
+
+/// Convert Dolby Vision to bt709 SDR with libplacebo, which is the only ffmpeg filter that
+/// applies the RPU reshaping profile 5 depends on (zscale would leave it purple and green)
+fn reshape_filter(target: &Target) -> String {
+    format!(
+        "libplacebo=tonemapping={}:colorspace=bt709:color_primaries=bt709:color_trc=bt709:range=tv:format={}",
+        target.encode.tonemap.as_deref().unwrap_or("hable"),
+        target.default.pix_fmt
+    )
+}
+
+/// Convert HDR (PQ or HLG) to bt709 SDR on the CPU: linearize, tonemap in floating point and
+/// then convert to bt709 in the target's pixel format
+fn tonemap_filter(target: &Target) -> String {
+    [
+        "zscale=t=linear:npl=100".to_string(),
+        "format=gbrpf32le".to_string(),
+        "zscale=p=bt709".to_string(),
+        format!(
+            "tonemap=tonemap={}:desat=0",
+            target.encode.tonemap.as_deref().unwrap_or("hable")
+        ),
+        "zscale=t=bt709:m=bt709:r=tv".to_string(),
+        format!("format={}", target.default.pix_fmt),
+    ]
+    .join(",")
+}
+
+/// Prefer the highest rate within `max` that evenly divides `rate`, so that frames are dropped
+/// at regular intervals (e.g. 59.94 becomes 29.97 rather than 30), unless that would throw
+/// away much more than needed
+fn reduced_frame_rate(rate: f64, max: f64) -> String {
+    let divided = rate / (rate / max).ceil();
+    let reduced = if divided >= max * 0.8 { divided } else { max };
+    // snapping to a nearby exact rate must not go over the limit
+    let rounded = reduced.round();
+    if (reduced - rounded).abs() < 0.01 && rounded <= max {
+        return format!("{}", rounded);
+    }
+    // NTSC rates, e.g. 30000/1001
+    let ntsc = (reduced * 1.001).round();
+    let ntsc_rate = ntsc * 1000.0 / 1001.0;
+    if (ntsc_rate - reduced).abs() < 0.01 && ntsc_rate <= max {
+        return format!("{}/1001", ntsc * 1000.0);
+    }
+    format!("{:.3}", (reduced * 1000.0).floor() / 1000.0)
+}
+
+/// Shrink a frame to fit within the limits, keeping its aspect ratio and even dimensions as
+/// most encoders require
+fn scaled_size(
+    width: i64,
+    height: i64,
+    max_width: Option<i64>,
+    max_height: Option<i64>,
+) -> (i64, i64) {
+    let scale = [
+        max_width.map(|max| max as f64 / width as f64),
+        max_height.map(|max| max as f64 / height as f64),
+    ]
+    .into_iter()
+    .flatten()
+    .fold(1.0, f64::min);
+
+    let even = |size: i64| (((size as f64 * scale) / 2.0).floor() as i64 * 2).max(2);
+    (even(width), even(height))
+}
+
+fn other_kind(stream: &OtherMetadata) -> StreamKind {
+    if stream.attached_pic {
+        StreamKind::CoverArt
+    } else if stream.codec_type == "attachment" {
+        StreamKind::Attachment
+    } else {
+        StreamKind::Data
+    }
+}
+
+/// Carry over streams the target doesn't care about as long as the output container can
+/// hold them; only Matroska supports attachments (e.g. subtitle fonts) and data tracks are
+/// only kept when the container doesn't change
+fn other_action(container: &str, kind: StreamKind, same_container: bool) -> StreamAction {
+    let supported = match kind {
+        StreamKind::Attachment => container == "mkv",
+        StreamKind::CoverArt => matches!(container, "mkv" | "mp4" | "m4v" | "mov"),
+        _ => same_container,
+    };
+    if supported {
+        StreamAction::Copy
+    } else {
+        StreamAction::Drop
+    }
+}
+
+impl StreamKind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            StreamKind::Video => "video",
+            StreamKind::Audio => "audio",
+            StreamKind::Subtitle => "subtitle",
+            StreamKind::CoverArt => "cover_art",
+            StreamKind::Attachment => "attachment",
+            StreamKind::Data => "data",
+        }
+    }
+}
+
+impl StreamAction {
+    /// The value to pass to ffmpeg's `-c` option for this stream
+    fn codec(&self) -> &str {
+        match self {
+            StreamAction::Copy | StreamAction::Drop => "copy",
+            StreamAction::Transcode(codec) => codec,
+        }
+    }
+}
+
+pub(crate) fn reencode(
+    plan: FixPlan,
+    metadata: &FileMetadata,
+    target: &Target,
+) -> anyhow::Result<()> {
+    let FixPlan {
+        out_path, command, ..
+    } = plan;
+
+    guard_terminal_size(100);
+
+    debug!("{:?}", command);
+
+    let result = run_ffmpeg(command).and_then(|()| verify_fix(&out_path, metadata, target));
+
+    if result.is_err() && out_path.exists() {
+        debug!("removing failed fix output {}", out_path.display());
+        fs::remove_file(&out_path)
+            .with_context(|| format!("could not remove {}", out_path.display()))?;
+    }
+
+    result
+}
+
+fn run_ffmpeg(mut cmd: Command) -> anyhow::Result<()> {
+    let status = cmd.spawn().context("could not start ffmpeg")?.wait()?;
+
+    if !status.success() {
+        bail!("ffmpeg failed ({})", status);
+    }
+
+    Ok(())
+}
+
+/// Check that a freshly written fix is actually playable on the target and wasn't cut short
+fn verify_fix(out_path: &Path, source: &FileMetadata, target: &Target) -> anyhow::Result<()> {
+    let fixed = metadata::get_metadata(out_path)?;
+    check_fixed(source, &fixed, target)
+        .with_context(|| format!("fixed output {} failed verification", out_path.display()))
+}
+
+/// Compare the probed output of a fix against its source
+fn check_fixed(source: &FileMetadata, fixed: &FileMetadata, target: &Target) -> anyhow::Result<()> {
+    // checks no fix can correct fail just the same on the output
+    let validation = validation::validate_format(fixed, &target.format_spec);
+    if validation.fixable().next().is_some() {
+        bail!(
+            "still not valid for target \"{}\": {}",
+            target.name,
+            validation.fixable().map(|c| &c.reason).join("; ")
+        );
+    }
+
+    // audio and subtitle tracks the fix drops can run on past the video, so it is the video
+    // that has to come out as long as before
+    let length = |file: &FileMetadata| {
+        file.video
+            .first()
+            .and_then(|v| v.duration)
+            .or(file.duration)
+    };
+    if let Some(expected) = length(source) {
+        let actual = length(fixed).unwrap_or(0.0);
+        if (expected - actual).abs() > MAX_DURATION_DIFFERENCE {
+            bail!(
+                "{:.2} minutes long but the source is {:.2} minutes",
+                actual,
+                expected
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn guard_terminal_size(min_width: u16) {
+    if let Some((Width(w), _)) = terminal_size() {
+        if w < min_width {
+            println!("Terminal width is below minimum size for nice ffmpeg output. Hit enter to continue.");
+            let _ = stdin().read_line(&mut String::new());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metadata::{AudioMetadata, DolbyVision, HdrFormat, SubtitleMetadata, VideoMetadata};
+    use crate::{
+        AudioSelection, AudioStrategy, DefaultFormat, EncodeOptions, FormatSpec, LanguageSpec,
+        VideoProfileSpec,
+    };
+
+    fn mk_target(format_spec: FormatSpec) -> Target {
+        Target {
+            name: "test".to_string(),
+            format_spec,
+            default: DefaultFormat {
+                audio: "aac".to_string(),
+                video: "h264".to_string(),
+                pix_fmt: "yuv420p".to_string(),
+                container: "mp4".to_string(),
+            },
+            encode: EncodeOptions::default(),
+        }
+    }
+
+    fn mk_spec() -> FormatSpec {
+        FormatSpec {
+            audio: Formats::Reject(vec!["opus".to_string()]),
+            video: Formats::Reject(vec!["hevc".to_string()]),
+            subtitle: Formats::Reject(vec!["hdmv_pgs_subtitle".to_string()]),
+            ..FormatSpec::default()
+        }
+    }
+
+    /// Plan a fix for `metadata` as `/nonexistent/movie.mkv`, without sidecars
+    fn mk_plan(metadata: &FileMetadata, target: &Target) -> FixPlan {
+        mk_plan_at(Path::new("/nonexistent/movie.mkv"), metadata, target)
+    }
+
+    fn mk_plan_at(path: &Path, metadata: &FileMetadata, target: &Target) -> FixPlan {
+        let validation = validation::validate_format(metadata, &target.format_spec);
+        FixPlan::new(path, metadata, &validation, &[], target).unwrap()
+    }
+
+    fn mk_metadata() -> FileMetadata {
+        FileMetadata {
+            container: "matroska".to_string(),
+            duration: None,
+            video: vec![VideoMetadata {
+                index: 0,
+                codec: "h264".to_string(),
+                codec_tag: None,
+                pix_fmt: "yuv420p".to_string(),
+                duration: None,
+                width: Some(1920),
+                height: Some(1080),
+                frame_rate: Some(24.0),
+                field_order: Some("progressive".to_string()),
+                bit_rate: Some(8_000_000),
+                profile: Some("High".to_string()),
+                level: Some(4.0),
+                color_transfer: Some("bt709".to_string()),
+                color_primaries: Some("bt709".to_string()),
+                color_space: Some("bt709".to_string()),
+                hdr: HdrFormat::Sdr,
+                dolby_vision: None,
+            }],
+            audio: vec![
+                AudioMetadata {
+                    index: 1,
+                    codec: "aac".to_string(),
+                    channels: 2,
+                    channel_layout: Some("stereo".to_string()),
+                    sample_rate: Some("48000".to_string()),
+                    language: Some("eng".to_string()),
+                    disposition: Disposition::default(),
+                },
+                AudioMetadata {
+                    index: 2,
+                    codec: "opus".to_string(),
+                    channels: 2,
+                    channel_layout: Some("stereo".to_string()),
+                    sample_rate: Some("48000".to_string()),
+                    language: Some("eng".to_string()),
+                    disposition: Disposition::default(),
+                },
+            ],
+            subtitle: vec![
+                SubtitleMetadata {
+                    index: 3,
+                    codec: "subrip".to_string(),
+                    language: Some("eng".to_string()),
+                    title: None,
+                    disposition: Disposition::default(),
+                },
+                SubtitleMetadata {
+                    index: 4,
+                    codec: "hdmv_pgs_subtitle".to_string(),
+                    language: Some("eng".to_string()),
+                    title: None,
+                    disposition: Disposition::default(),
+                },
+            ],
+            other: vec![],
+        }
+    }
+
+    fn args(plan: &FixPlan) -> Vec<String> {
+        plan.command
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn plan_transcodes_only_invalid_streams() {
+        let target = mk_target(mk_spec());
+        let metadata = mk_metadata();
+
+        let plan = mk_plan(&metadata, &target);
+
+        let actions = plan.streams.iter().map(|s| &s.action).collect_vec();
+        assert_eq!(
+            actions,
+            vec![
+                &StreamAction::Copy,
+                &StreamAction::Copy,
+                &StreamAction::Transcode("aac".to_string()),
+                &StreamAction::Copy,
+                &StreamAction::Drop,
+            ]
+        );
+
+        let args = args(&plan);
+        assert!(!args.contains(&"0:4".to_string()));
+        assert!(args.windows(2).any(|w| w == ["-c:2", "aac"]));
+        assert_eq!(plan.out_path, Path::new("/nonexistent/movie.fixed.mkv"));
+    }
+
+    #[test]
+    fn command_line_is_shell_quoted() {
+        let target = mk_target(mk_spec());
+        let metadata = mk_metadata();
+
+        let plan = mk_plan_at(Path::new("/nonexistent/a movie.mkv"), &metadata, &target);
+
+        assert!(plan
+            .command_line()
+            .contains("-i '/nonexistent/a movie.mkv'"));
+    }
+
+    #[test]
+    fn plan_remuxes_when_only_container_is_invalid() {
+        let mut spec = mk_spec();
+        spec.container = Formats::Allow(vec!["mov".to_string()]);
+        spec.subtitle = Formats::Reject(vec![]);
+        let target = mk_target(spec);
+        let mut metadata = mk_metadata();
+        metadata.audio.pop();
+
+        let plan = mk_plan(&metadata, &target);
+
+        assert_eq!(plan.out_path, Path::new("/nonexistent/movie.fixed.mp4"));
+        assert_eq!(plan.streams[0].action, StreamAction::Copy);
+        assert_eq!(plan.streams[1].action, StreamAction::Copy);
+        // subrip has to become mov_text and PGS can't be carried in mp4 at all
+        assert_eq!(
+            plan.streams[2].action,
+            StreamAction::Transcode("mov_text".to_string())
+        );
+        assert_eq!(plan.streams[3].action, StreamAction::Drop);
+    }
+
+    #[test]
+    fn plan_keeps_valid_container() {
+        let target = mk_target(mk_spec());
+        let metadata = mk_metadata();
+
+        let plan = mk_plan_at(Path::new("/nonexistent/movie.MKV"), &metadata, &target);
+
+        assert_eq!(plan.out_path, Path::new("/nonexistent/movie.fixed.mkv"));
+        assert!(!plan.is_remux());
+    }
+
+    #[test]
+    fn plan_leaves_webm_for_codecs_it_cant_hold() {
+        let target = mk_target(mk_spec());
+        let mut metadata = mk_metadata();
+        metadata.video[0].codec = "vp9".to_string();
+        metadata.audio.remove(0);
+        metadata.subtitle.clear();
+
+        // opus is transcoded to aac, which the webm muxer rejects
+        let plan = mk_plan_at(Path::new("/nonexistent/movie.webm"), &metadata, &target);
+        assert_eq!(plan.out_path, Path::new("/nonexistent/movie.fixed.mp4"));
+        assert_eq!(plan.streams[0].action, StreamAction::Copy);
+        assert_eq!(
+            plan.streams[1].action,
+            StreamAction::Transcode("aac".to_string())
+        );
+
+        // dropping a stream leaves nothing webm can't hold
+        let mut spec = mk_spec();
+        spec.audio = Formats::Reject(vec![]);
+        spec.subtitle = Formats::Reject(vec!["webvtt".to_string()]);
+        let target = mk_target(spec);
+        metadata.subtitle.push(SubtitleMetadata {
+            index: 2,
+            codec: "webvtt".to_string(),
+            language: None,
+            title: None,
+            disposition: Disposition::default(),
+        });
+        let plan = mk_plan_at(Path::new("/nonexistent/movie.webm"), &metadata, &target);
+        assert_eq!(plan.out_path, Path::new("/nonexistent/movie.fixed.webm"));
+    }
+
+    #[test]
+    fn plan_keeps_attachments_and_cover_art() {
+        let target = mk_target(mk_spec());
+        let mut metadata = mk_metadata();
+        metadata.other = vec![
+            OtherMetadata {
+                index: 5,
+                codec_type: "attachment".to_string(),
+                codec: Some("ttf".to_string()),
+                attached_pic: false,
+            },
+            OtherMetadata {
+                index: 6,
+                codec_type: "video".to_string(),
+                codec: Some("mjpeg".to_string()),
+                attached_pic: true,
+            },
+        ];
+
+        let plan = mk_plan(&metadata, &target);
+
+        assert_eq!(plan.streams[5].kind, StreamKind::Attachment);
+        assert_eq!(plan.streams[5].action, StreamAction::Copy);
+        assert_eq!(plan.streams[6].kind, StreamKind::CoverArt);
+        assert_eq!(plan.streams[6].action, StreamAction::Copy);
+
+        let args = args(&plan);
+        assert!(args.windows(2).any(|w| w == ["-map", "0:5"]));
+        assert!(args.windows(2).any(|w| w == ["-map_chapters", "0"]));
+        assert!(args.windows(2).any(|w| w == ["-map_metadata", "0"]));
+    }
+
+    #[test]
+    fn plan_applies_encode_options_to_transcoded_streams() {
+        let mut target = mk_target(mk_spec());
+        target.encode = EncodeOptions {
+            video_quality: Some(VideoQuality::Crf(20)),
+            preset: Some("slow".to_string()),
+            audio_bitrate: Some("192k".to_string()),
+            extra_args: vec!["-movflags".to_string(), "+faststart".to_string()],
+            ..EncodeOptions::default()
+        };
+        let mut metadata = mk_metadata();
+        metadata.video[0].codec = "hevc".to_string();
+
+        let plan = mk_plan(&metadata, &target);
+
+        let args = args(&plan);
+        assert!(args.windows(2).any(|w| w == ["-crf:0", "20"]));
+        assert!(args.windows(2).any(|w| w == ["-preset:0", "slow"]));
+        assert!(args.windows(2).any(|w| w == ["-b:2", "192k"]));
+        // the copied aac stream is left alone
+        assert!(!args.contains(&"-b:1".to_string()));
+        assert_eq!(
+            args[args.len() - 3..],
+            ["-movflags", "+faststart", "/nonexistent/movie.fixed.mkv"]
+        );
+    }
+
+    #[test]
+    fn plan_caps_bitrate_when_over_limit() {
+        let mut spec = mk_spec();
+        spec.max_video_bitrate = Some(5_000_000);
+        let target = mk_target(spec);
+        let metadata = mk_metadata();
+
+        let plan = mk_plan(&metadata, &target);
+
+        assert_eq!(
+            plan.streams[0].action,
+            StreamAction::Transcode("h264".to_string())
+        );
+        let args = args(&plan);
+        assert!(args.windows(2).any(|w| w == ["-maxrate:0", "5000000"]));
+        assert!(args.windows(2).any(|w| w == ["-metadata:s:0", "BPS="]));
+        assert!(args.windows(2).any(|w| w == ["-bufsize:0", "10000000"]));
+    }
+
+    #[test]
+    fn plan_scales_and_reduces_frame_rate_over_limits() {
+        let mut spec = mk_spec();
+        spec.max_width = Some(1280);
+        spec.max_height = Some(720);
+        spec.max_frame_rate = Some(30.0);
+        let mut target = mk_target(spec);
+        target.encode.scaler = Some("lanczos".to_string());
+        let mut metadata = mk_metadata();
+        metadata.video[0].frame_rate = Some(60000.0 / 1001.0);
+
+        let plan = mk_plan(&metadata, &target);
+
+        let args = args(&plan);
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["-filter:0", "fps=30000/1001,scale=1280:720:flags=lanczos"]));
+    }
+
+    #[test]
+    fn scaled_size_keeps_aspect_ratio() {
+        assert_eq!(
+            scaled_size(3840, 2160, Some(1920), Some(1080)),
+            (1920, 1080)
+        );
+        // a wide 2.39:1 frame is limited by its width
+        assert_eq!(scaled_size(3840, 1606, Some(1920), Some(1080)), (1920, 802));
+        assert_eq!(scaled_size(1440, 1080, None, Some(720)), (960, 720));
+    }
+
+    #[test]
+    fn reduced_frame_rate_stays_within_max() {
+        assert_eq!(reduced_frame_rate(60.0, 30.0), "30");
+        assert_eq!(reduced_frame_rate(50.0, 30.0), "25");
+        assert_eq!(reduced_frame_rate(24.0, 20.0), "20");
+        assert_eq!(reduced_frame_rate(60000.0 / 1001.0, 30.0), "30000/1001");
+        // 30000/1001 is just over a 29.97 limit
+        assert_eq!(reduced_frame_rate(30.0, 29.97), "29.970");
+        assert_eq!(reduced_frame_rate(30.0, 29.995), "29.995");
+    }
+
+    #[test]
+    fn plan_keeps_transcodes_within_profile_rules() {
+        let mut spec = mk_spec();
+        spec.video_profiles.insert(
+            "h264".to_string(),
+            VideoProfileSpec {
+                profile: Formats::Allow(vec!["Main".to_string(), "High".to_string()]),
+                max_level: Some(4.1),
+            },
+        );
+        let target = mk_target(spec);
+        let mut metadata = mk_metadata();
+        metadata.video[0].profile = Some("High 4:4:4 Predictive".to_string());
+
+        let plan = mk_plan(&metadata, &target);
+
+        let args = args(&plan);
+        assert!(args.windows(2).any(|w| w == ["-c:0", "h264"]));
+        assert!(args.windows(2).any(|w| w == ["-profile:0", "high"]));
+        assert!(args.windows(2).any(|w| w == ["-level:0", "4.1"]));
+    }
+
+    #[test]
+    fn plan_converts_10_bit_sources_for_the_encoder_profile() {
+        let mut spec = mk_spec();
+        spec.video = Formats::Reject(vec!["vp9".to_string()]);
+        spec.video_profiles.insert(
+            "h264".to_string(),
+            VideoProfileSpec {
+                profile: Formats::Allow(vec!["Main".to_string(), "High".to_string()]),
+                max_level: None,
+            },
+        );
+        let target = mk_target(spec);
+        let mut metadata = mk_metadata();
+        metadata.video[0].codec = "vp9".to_string();
+        metadata.video[0].pix_fmt = "yuv420p10le".to_string();
+
+        // the pixel format isn't rejected, but High can't hold 10-bit
+        let plan = mk_plan(&metadata, &target);
+        let args = args(&plan);
+        assert!(args.windows(2).any(|w| w == ["-pix_fmt:0", "yuv420p"]));
+        assert!(args.windows(2).any(|w| w == ["-profile:0", "high"]));
+    }
+
+    #[test]
+    fn plan_downmixes_only_streams_over_the_channel_limit() {
+        let mut spec = mk_spec();
+        spec.audio = Formats::Reject(vec![]);
+        spec.max_audio_channels = Some(2);
+        spec.audio_sample_rate = Some(Formats::Allow(vec![
+            "44100".to_string(),
+            "48000".to_string(),
+        ]));
+        let mut target = mk_target(spec);
+        let mut metadata = mk_metadata();
+        metadata.audio[1].channels = 6;
+        metadata.audio[1].sample_rate = Some("96000".to_string());
+
+        let plan = mk_plan(&metadata, &target);
+
+        assert_eq!(plan.streams[1].action, StreamAction::Copy);
+        let resampled = args(&plan);
+        assert!(resampled.windows(2).any(|w| w == ["-ac:2", "2"]));
+        assert!(resampled
+            .windows(2)
+            .any(|w| w == ["-filter:2", "aformat=sample_rates=44100|48000"]));
+
+        target.encode.downmix_filter = Some("pan=stereo|FL=FC+0.3*FL|FR=FC+0.3*FR".to_string());
+        let plan = mk_plan(&metadata, &target);
+        let args = args(&plan);
+        assert!(!args.contains(&"-ac:2".to_string()));
+        assert!(args.windows(2).any(|w| w
+            == [
+                "-filter:2",
+                "pan=stereo|FL=FC+0.3*FL|FR=FC+0.3*FR,aformat=sample_rates=44100|48000"
+            ]));
+    }
+
+    #[test]
+    fn plan_adds_compatible_audio_alongside_the_original() {
+        let mut spec = mk_spec();
+        spec.audio = Formats::Allow(vec!["aac".to_string()]);
+        spec.audio_strategy = AudioStrategy::AddCompat;
+        let target = mk_target(spec);
+        let mut metadata = mk_metadata();
+        metadata.audio[0].codec = "truehd".to_string();
+        metadata.audio.pop();
+
+        let plan = mk_plan(&metadata, &target);
+
+        let added = plan.streams.last().unwrap();
+        assert!(added.added);
+        assert_eq!(added.index, 1);
+        assert_eq!(added.action, StreamAction::Transcode("aac".to_string()));
+        assert!(!plan.is_remux());
+
+        let args = args(&plan);
+        let maps = args
+            .windows(2)
+            .filter(|w| w[0] == "-map")
+            .map(|w| w[1].as_str())
+            .collect_vec();
+        // video, the original truehd, the subrip subtitle, then the added aac copy
+        assert_eq!(maps, ["0:0", "0:1", "0:3", "0:1"]);
+        assert!(args.windows(2).any(|w| w == ["-c:1", "copy"]));
+        assert!(args.windows(2).any(|w| w == ["-disposition:1", "0"]));
+        assert!(args.windows(2).any(|w| w == ["-c:3", "aac"]));
+        assert!(args.windows(2).any(|w| w == ["-disposition:3", "default"]));
+    }
+
+    #[test]
+    fn plan_tonemaps_rejected_hdr_after_scaling() {
+        let mut spec = mk_spec();
+        spec.hdr = Some(Formats::Allow(vec!["sdr".to_string()]));
+        spec.max_height = Some(720);
+        let target = mk_target(spec);
+        let mut metadata = mk_metadata();
+        metadata.video[0].hdr = HdrFormat::Hdr10;
+
+        let plan = mk_plan(&metadata, &target);
+
+        let args = args(&plan);
+        let filter = args
+            .windows(2)
+            .find(|w| w[0] == "-filter:0")
+            .map(|w| w[1].clone())
+            .unwrap();
+        assert!(filter.starts_with("scale=1280:720,zscale=t=linear"));
+        assert!(filter.contains("tonemap=tonemap=hable"));
+        assert!(filter.ends_with("format=yuv420p"));
+        assert!(args.windows(2).any(|w| w == ["-color_trc:0", "bt709"]));
+    }
+
+    #[test]
+    fn plan_removes_dolby_vision_by_profile() {
+        let mut spec = mk_spec();
+        spec.video = Formats::Reject(vec![]);
+        spec.dolby_vision_profile = Some(Formats::Reject(vec![]));
+        spec.hdr = Some(Formats::Reject(vec!["dolby-vision".to_string()]));
+        let target = mk_target(spec);
+        let mut metadata = mk_metadata();
+        metadata.video[0].codec = "hevc".to_string();
+        metadata.video[0].hdr = HdrFormat::DolbyVision;
+        metadata.video[0].dolby_vision = Some(DolbyVision {
+            profile: 8,
+            level: Some(6),
+            base_layer: Some(HdrFormat::Hdr10),
+        });
+        let plan = mk_plan(&metadata, &target);
+
+        // profile 8 falls back to its HDR10 base layer, which the target accepts
+        assert_eq!(plan.streams[0].action, StreamAction::Copy);
+        assert!(args(&plan).windows(2).any(|w| w == ["-bsf:0", "dovi_rm"]));
+
+        metadata.video[0].dolby_vision = Some(DolbyVision {
+            profile: 5,
+            level: Some(6),
+            base_layer: None,
+        });
+        let plan = mk_plan(&metadata, &target);
+
+        assert_eq!(
+            plan.streams[0].action,
+            StreamAction::Transcode("h264".to_string())
+        );
+        let reshaped = args(&plan);
+        assert!(!reshaped.contains(&"dovi_rm".to_string()));
+        assert!(reshaped
+            .iter()
+            .any(|a| a.starts_with("libplacebo=tonemapping=hable")));
+    }
+
+    #[test]
+    fn plan_rewrites_codec_tag_without_reencoding() {
+        let mut spec = mk_spec();
+        spec.video = Formats::Reject(vec![]);
+        spec.video_codec_tag = Some(Formats::Allow(vec!["hvc1".to_string()]));
+        let target = mk_target(spec);
+        let mut metadata = mk_metadata();
+        metadata.container = "mov".to_string();
+        metadata.video[0].codec = "hevc".to_string();
+        metadata.video[0].codec_tag = Some("hev1".to_string());
+        metadata.subtitle.clear();
+        let plan = mk_plan_at(Path::new("/nonexistent/movie.mp4"), &metadata, &target);
+
+        assert_eq!(plan.streams[0].action, StreamAction::Copy);
+        assert!(args(&plan).windows(2).any(|w| w == ["-tag:0", "hvc1"]));
+
+        // vp9 can't be given an hvc1 tag, so it has to be reencoded
+        metadata.video[0].codec = "vp9".to_string();
+        metadata.video[0].codec_tag = Some("vp09".to_string());
+        let plan = mk_plan_at(Path::new("/nonexistent/movie.mp4"), &metadata, &target);
+        assert_eq!(
+            plan.streams[0].action,
+            StreamAction::Transcode("h264".to_string())
+        );
+    }
+
+    #[test]
+    fn plan_deinterlaces_before_scaling() {
+        let mut spec = mk_spec();
+        spec.interlaced = Some(false);
+        spec.max_height = Some(720);
+        let mut target = mk_target(spec);
+        let mut metadata = mk_metadata();
+        metadata.video[0].field_order = Some("bb".to_string());
+
+        let plan = mk_plan(&metadata, &target);
+        assert!(args(&plan)
+            .windows(2)
+            .any(|w| w == ["-filter:0", "bwdif=mode=send_frame,scale=1280:720"]));
+
+        target.encode.deinterlace_filter = Some("yadif".to_string());
+        let plan = mk_plan(&metadata, &target);
+        assert!(args(&plan)
+            .windows(2)
+            .any(|w| w == ["-filter:0", "yadif,scale=1280:720"]));
+    }
+
+    #[test]
+    fn plan_flags_and_moves_preferred_audio_first() {
+        let mut spec = mk_spec();
+        spec.audio = Formats::Reject(vec![]);
+        spec.languages = Some(LanguageSpec {
+            audio: vec!["eng".to_string()],
+            default_audio: DefaultAudio::PreferredFirst,
+            audio_selection: None,
+            flag_forced_subtitles: true,
+        });
+        let target = mk_target(spec);
+        let mut metadata = mk_metadata();
+        metadata.audio[0].language = Some("jpn".to_string());
+        metadata.audio[0].disposition.default = true;
+        metadata.audio[1].disposition.comment = true;
+        metadata.subtitle[0].title = Some("Signs & Songs (forced)".to_string());
+        metadata.subtitle.pop();
+
+        let plan = mk_plan(&metadata, &target);
+
+        assert!(plan.is_remux());
+        let args = args(&plan);
+        let maps = args
+            .windows(2)
+            .filter(|w| w[0] == "-map")
+            .map(|w| w[1].as_str())
+            .collect_vec();
+        assert_eq!(maps, ["0:0", "0:2", "0:1", "0:3"]);
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["-disposition:1", "default+comment"]));
+        assert!(args.windows(2).any(|w| w == ["-disposition:2", "0"]));
+        assert!(args.windows(2).any(|w| w == ["-disposition:3", "forced"]));
+    }
+
+    #[test]
+    fn plan_defaults_to_compatible_copy_in_preferred_language() {
+        let mut spec = mk_spec();
+        spec.audio = Formats::Allow(vec!["aac".to_string()]);
+        spec.audio_strategy = AudioStrategy::AddCompat;
+        spec.languages = Some(LanguageSpec {
+            audio: vec!["eng".to_string()],
+            default_audio: DefaultAudio::Preferred,
+            audio_selection: None,
+            flag_forced_subtitles: false,
+        });
+        let target = mk_target(spec);
+        let mut metadata = mk_metadata();
+        metadata.audio[0].codec = "dts".to_string();
+        metadata.audio[0].language = Some("fre".to_string());
+        metadata.audio[1].codec = "truehd".to_string();
+
+        let plan = mk_plan(&metadata, &target);
+
+        // the added copies of the dts and truehd streams come last
+        let added = &plan.streams[plan.streams.len() - 2..];
+        assert!(added.iter().all(|s| s.added));
+        assert_eq!(added[1].index, 2);
+        let args = args(&plan);
+        // the subrip subtitle sits between the originals and the copies
+        assert!(args.windows(2).any(|w| w == ["-disposition:1", "0"]));
+        assert!(args.windows(2).any(|w| w == ["-disposition:4", "0"]));
+        assert!(args.windows(2).any(|w| w == ["-disposition:5", "default"]));
+    }
+
+    #[test]
+    fn plan_transcodes_only_preferred_language_audio() {
+        let mut spec = mk_spec();
+        spec.audio = Formats::Allow(vec!["aac".to_string()]);
+        spec.languages = Some(LanguageSpec {
+            audio: vec!["eng".to_string()],
+            default_audio: DefaultAudio::Any,
+            audio_selection: Some(AudioSelection::PreferredOnly),
+            flag_forced_subtitles: false,
+        });
+        let target = mk_target(spec);
+        let mut metadata = mk_metadata();
+        metadata.audio[0].language = Some("jpn".to_string());
+        metadata.audio[1].codec = "flac".to_string();
+        let plan = mk_plan(&metadata, &target);
+
+        assert_eq!(plan.streams[1].action, StreamAction::Drop);
+        assert_eq!(
+            plan.streams[2].action,
+            StreamAction::Transcode("aac".to_string())
+        );
+        let args = args(&plan);
+        assert!(!args.contains(&"0:1".to_string()));
+        assert!(args.windows(2).any(|w| w == ["-c:1", "aac"]));
+
+        // the original language stream is copied even though the target can't play it
+        let mut spec = mk_spec();
+        spec.audio = Formats::Allow(vec!["aac".to_string()]);
+        spec.languages = Some(LanguageSpec {
+            audio: vec!["eng".to_string()],
+            default_audio: DefaultAudio::Any,
+            audio_selection: Some(AudioSelection::PreferredAndOriginal),
+            flag_forced_subtitles: false,
+        });
+        let target = mk_target(spec);
+        metadata.audio[0].codec = "truehd".to_string();
+        let plan = mk_plan(&metadata, &target);
+
+        assert_eq!(plan.streams[1].action, StreamAction::Copy);
+        assert_eq!(
+            plan.streams[2].action,
+            StreamAction::Transcode("aac".to_string())
+        );
+    }
+
+    #[test]
+    fn plan_muxes_sidecar_subtitles() {
+        let target = mk_target(mk_spec());
+        let metadata = mk_metadata();
+        let validation = validation::validate_format(&metadata, &target.format_spec);
+        let sidecar = |name: &str, codec: &str, forced| Sidecar {
+            path: PathBuf::from("/nonexistent").join(name),
+            codec: codec.to_string(),
+            language: Some("eng".to_string()),
+            forced,
+            hearing_impaired: false,
+        };
+        let sidecars = [
+            sidecar("movie.en.forced.srt", "subrip", true),
+            sidecar("movie.en.sup", "hdmv_pgs_subtitle", false),
+            sidecar("movie.en.ass", "ass", false),
+        ];
+
+        let plan = FixPlan::new(
+            Path::new("/nonexistent/movie.mkv"),
+            &metadata,
+            &validation,
+            &sidecars,
+            &target,
+        )
+        .unwrap();
+
+        // the target rejects PGS, so that sidecar is left out like the PGS stream
+        let actions = plan.streams[5..].iter().map(|s| &s.action).collect_vec();
+        assert_eq!(
+            actions,
+            [
+                &StreamAction::Copy,
+                &StreamAction::Drop,
+                &StreamAction::Copy
+            ]
+        );
+        let args = args(&plan);
+        let inputs = args
+            .windows(2)
+            .filter(|w| w[0] == "-i")
+            .map(|w| w[1].as_str())
+            .collect_vec();
+        assert_eq!(
+            inputs,
+            [
+                "/nonexistent/movie.mkv",
+                "/nonexistent/movie.en.forced.srt",
+                "/nonexistent/movie.en.ass"
+            ]
+        );
+        assert!(args.windows(2).any(|w| w == ["-map", "1:0"]));
+        assert!(args.windows(2).any(|w| w == ["-map", "2:0"]));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["-metadata:s:4", "language=eng"]));
+        assert!(args.windows(2).any(|w| w == ["-disposition:4", "forced"]));
+        assert!(!args.contains(&"-disposition:5".to_string()));
+    }
+
+    #[test]
+    fn plan_add_compat_keeps_incompatible_audio_when_fixing_video() {
+        let mut spec = mk_spec();
+        spec.audio = Formats::Allow(vec!["aac".to_string()]);
+        spec.audio_strategy = AudioStrategy::AddCompat;
+        let target = mk_target(spec);
+        let mut metadata = mk_metadata();
+        metadata.video[0].codec = "hevc".to_string();
+        metadata.audio[1].codec = "truehd".to_string();
+        let validation = validation::validate_format(&metadata, &target.format_spec);
+        assert!(validation.compatible_audio.as_ref().unwrap().okay);
+
+        let plan = mk_plan(&metadata, &target);
+
+        assert_eq!(
+            plan.streams[0].action,
+            StreamAction::Transcode("h264".to_string())
+        );
+        // the aac stream is already compatible, so the truehd one is kept untouched and
+        // nothing is added
+        assert_eq!(plan.streams[1].action, StreamAction::Copy);
+        assert_eq!(plan.streams[2].action, StreamAction::Copy);
+        assert!(plan.streams.iter().all(|s| !s.added));
+    }
+
+    #[test]
+    fn plan_deinterlaces_without_doubling_the_frame_rate() {
+        let mut spec = mk_spec();
+        spec.interlaced = Some(false);
+        spec.max_frame_rate = Some(30.0);
+        let target = mk_target(spec);
+        let mut metadata = mk_metadata();
+        metadata.video[0].field_order = Some("tt".to_string());
+        metadata.video[0].frame_rate = Some(30000.0 / 1001.0);
+
+        let plan = mk_plan(&metadata, &target);
+
+        // 29.97i has to stay 29.97p to stay within the limit
+        assert!(args(&plan)
+            .windows(2)
+            .any(|w| w == ["-filter:0", "bwdif=mode=send_frame"]));
+    }
+
+    #[test]
+    fn fixed_output_is_checked_against_the_source() {
+        let target = mk_target(mk_spec());
+        let mut source = mk_metadata();
+        source.duration = Some(90.0);
+        let mut fixed = mk_metadata();
+        fixed.audio.pop();
+        fixed.subtitle.pop();
+
+        // container durations are rounded slightly differently after a remux
+        fixed.duration = Some(90.0 + MAX_DURATION_DIFFERENCE / 2.0);
+        assert!(check_fixed(&source, &fixed, &target).is_ok());
+
+        fixed.duration = Some(45.0);
+        let err = check_fixed(&source, &fixed, &target).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "45.00 minutes long but the source is 90.00 minutes"
+        );
+
+        // a dropped commentary track ran past the end of the video
+        source.duration = Some(92.0);
+        source.video[0].duration = Some(90.0);
+        fixed.duration = Some(90.0);
+        fixed.video[0].duration = Some(90.0);
+        assert!(check_fixed(&source, &fixed, &target).is_ok());
+        fixed.video[0].duration = Some(60.0);
+        assert!(check_fixed(&source, &fixed, &target).is_err());
+
+        fixed.video[0].duration = Some(90.0);
+        fixed.video[0].codec = "hevc".to_string();
+        let err = check_fixed(&source, &fixed, &target).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("still not valid for target \"test\": "));
+    }
+
+    #[test]
+    fn fixed_output_may_still_fail_unfixable_checks() {
+        let mut spec = mk_spec();
+        spec.languages = Some(LanguageSpec {
+            audio: vec!["eng".to_string()],
+            default_audio: DefaultAudio::Any,
+            audio_selection: None,
+            flag_forced_subtitles: false,
+        });
+        let target = mk_target(spec);
+        let mut fixed = mk_metadata();
+        fixed.audio.pop();
+        fixed.subtitle.pop();
+        fixed.audio[0].language = Some("jpn".to_string());
+
+        assert!(check_fixed(&mk_metadata(), &fixed, &target).is_ok());
+    }
+}
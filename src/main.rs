@@ -1,42 +1,76 @@
 use std::{
-    env,
-    ffi::OsStr,
-    fs,
-    io::stdin,
+    collections::BTreeMap,
+    env, fs,
     path::{Path, PathBuf},
-    process::Command,
+    process::ExitCode,
 };
 
-use anyhow::{anyhow, bail, Context};
+use anyhow::{anyhow, Context};
 use clap::Parser;
 use directories::ProjectDirs;
 use env_logger::Builder;
-use log::{debug, LevelFilter};
-use metadata::FileMetadata;
+use fix::FixPlan;
+use log::LevelFilter;
+use paths::{Pattern, ScanOptions, SidecarIndex};
+use report::{FileReport, FixReport, FixStatus, OutputFormat, Reporter};
 use serde::{Deserialize, Serialize};
-use terminal_size::{terminal_size, Width};
-use validation::FormatValidation;
 
+mod fix;
+mod matrix;
 mod metadata;
+mod paths;
+mod report;
 mod validation;
 
-const VALID_EXTENSIONS: [&str; 6] = ["mkv", "mp4", "avi", "webm", "mov", "wmv"];
-
 #[derive(Debug, Parser)]
 #[command(version, about)]
 struct Args {
     #[arg(long)]
     fix: bool,
+    /// Print the ffmpeg command that --fix would run for each invalid file without running it
+    #[arg(long)]
+    dry_run: bool,
+    /// Add sidecar subtitle files (e.g. `Movie.en.forced.srt` beside `Movie.mkv`) to the
+    /// output of a fix
+    #[arg(long)]
+    mux_sidecars: bool,
     #[arg(long)]
     target: Option<String>,
-    path: Option<PathBuf>,
+    /// Files and directories to check (defaults to the current directory)
+    paths: Vec<PathBuf>,
+    /// Also check paths listed in this file (`-` for stdin), separated by newlines or NUL
+    /// characters
+    #[arg(long, value_name = "FILE")]
+    files_from: Option<PathBuf>,
     #[arg(long)]
     debug: bool,
     #[arg(long)]
     config: Option<PathBuf>,
+    /// Scan subdirectories as well
+    #[arg(long)]
+    recursive: bool,
+    /// How many directory levels to descend when scanning recursively
+    #[arg(long, requires = "recursive")]
+    max_depth: Option<usize>,
+    /// Follow symbolic links to directories when scanning recursively
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// Only check files matching this glob (may be repeated)
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+    /// Skip files and directories matching this glob, e.g. `*.fixed.mkv` or `Extras/` (may be
+    /// repeated)
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+    /// How to report results
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+    /// Check files against every configured target and report a file × target table
+    #[arg(long, conflicts_with_all = ["fix", "dry_run", "target"])]
+    matrix: bool,
 }
 
-fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<ExitCode> {
     let args = Args::parse();
 
     Builder::new()
@@ -49,51 +83,144 @@ fn main() -> anyhow::Result<()> {
 
     let config = load_config(args.config)?;
 
-    let check_path = args
-        .path
-        .ok_or_else(|| anyhow!("no path"))
-        .or_else(|_| env::current_dir())?;
-
-    let should_fix = args.fix;
+    let fix_mode = if args.dry_run {
+        FixMode::DryRun
+    } else if args.fix {
+        FixMode::Fix
+    } else {
+        FixMode::Off
+    };
 
     let requested_target = args.target.as_ref().unwrap_or(&config.default_target);
     let target = config.find_target(requested_target)?;
 
+    let scan_options = ScanOptions {
+        recursive: args.recursive,
+        max_depth: args.max_depth,
+        follow_symlinks: args.follow_symlinks,
+        include: args
+            .include
+            .iter()
+            .map(|p| Pattern::new(p))
+            .collect::<anyhow::Result<_>>()?,
+        exclude: args
+            .exclude
+            .iter()
+            .map(|p| Pattern::new(p))
+            .collect::<anyhow::Result<_>>()?,
+    };
+
+    let mut input_paths = args.paths;
+    if let Some(files_from) = &args.files_from {
+        input_paths.extend(paths::read_path_list(files_from)?);
+    }
+    if input_paths.is_empty() {
+        input_paths.push(env::current_dir()?);
+    }
+
     let mut check_paths: Vec<PathBuf> = Vec::new();
+    let mut sidecars = SidecarIndex::default();
 
-    if check_path.is_file() {
-        check_paths.push(check_path);
-    } else {
-        get_paths(&check_path, &mut check_paths)?;
+    for input_path in input_paths {
+        if input_path.is_dir() {
+            paths::get_paths(&input_path, &scan_options, &mut check_paths, &mut sidecars)?;
+        } else {
+            // anything that isn't a directory is checked directly so that a missing file
+            // shows up as an error for that file rather than ending the run
+            check_paths.push(input_path);
+        }
+    }
+
+    paths::dedup_paths(&mut check_paths);
+
+    if args.format == OutputFormat::Text && !args.matrix {
+        println!(
+            "Checking {} against target \"{}\"",
+            check_paths.len(),
+            requested_target
+        );
+    }
+    if args.matrix {
+        return matrix::check_files(&config.targets, &check_paths, args.format);
     }
 
-    println!(
-        "Checking {} against target \"{}\"",
-        check_paths.len(),
-        requested_target
-    );
+    let mut reporter = Reporter::new(args.format, &report::FILE_CSV_HEADER)?;
+    let mut summary = Summary::default();
     for path in check_paths {
         // TODO: prompt before reencoding?
-        handle_file(path, target, should_fix)?;
+        let file_report = handle_file(
+            &path,
+            target,
+            fix_mode,
+            args.mux_sidecars,
+            &mut sidecars,
+            args.format,
+        );
+        if let Some(err) = &file_report.error {
+            eprintln!("error handling {}: {}", path.display(), err);
+        }
+        reporter.write(&file_report)?;
+        summary.record(file_report);
     }
+    reporter.finish()?;
 
-    Ok(())
+    if args.format == OutputFormat::Text {
+        summary.print();
+    }
+
+    Ok(if summary.errors.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
 }
 
-fn get_paths(check_path: &Path, check_paths: &mut Vec<PathBuf>) -> anyhow::Result<()> {
-    let paths = fs::read_dir(check_path)?;
-    let extensions = VALID_EXTENSIONS.map(OsStr::new);
-    for entry in paths.flatten() {
-        let path = entry.path();
-        if path.is_file() {
-            if let Some(extension) = path.extension() {
-                if extensions.contains(&extension) {
-                    check_paths.push(path);
-                }
-            }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FixMode {
+    Off,
+    /// Work out how invalid files would be fixed without running ffmpeg
+    DryRun,
+    Fix,
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    valid: usize,
+    invalid: usize,
+    fixed: usize,
+    errors: Vec<(PathBuf, String)>,
+}
+
+impl Summary {
+    fn record(&mut self, file_report: FileReport) {
+        if let Some(err) = file_report.error {
+            self.errors.push((file_report.path, err));
+        } else if file_report
+            .fix
+            .as_ref()
+            .is_some_and(|f| f.status == FixStatus::Fixed)
+        {
+            self.fixed += 1;
+        } else if file_report.valid == Some(true) {
+            self.valid += 1;
+        } else {
+            self.invalid += 1;
+        }
+    }
+
+    fn print(&self) {
+        println!();
+        println!(
+            "{} valid, {} invalid, {} fixed, {} errored",
+            self.valid,
+            self.invalid,
+            self.fixed,
+            self.errors.len()
+        );
+        for (path, err) in &self.errors {
+            println!(" - {}: {}", path.display(), err);
         }
     }
-    Ok(())
 }
 
 fn load_config(config_override: Option<PathBuf>) -> anyhow::Result<Config> {
@@ -111,103 +238,90 @@ fn load_config(config_override: Option<PathBuf>) -> anyhow::Result<Config> {
     Ok(config)
 }
 
-fn handle_file(path: PathBuf, target: &Target, should_fix: bool) -> anyhow::Result<()> {
-    let metadata = metadata::get_metadata(&path)?;
-    let validation = validation::validate_format(&metadata, &target.format_spec);
-
-    report(&path, &metadata, &validation);
-
-    if !validation.is_valid() && should_fix {
-        reencode(&path, &validation, &target.default)?;
-    };
-    Ok(())
-}
-
-fn report(path: &Path, metadata: &FileMetadata, validation: &FormatValidation) {
-    println!();
-    println!(
-        "{}",
-        path.file_name().and_then(|n| n.to_str()).unwrap_or("..")
-    );
-    println!(
-        " - {} {}; {} {}; {} {}; {} {}",
-        metadata.audio.codec,
-        report_status(validation.audio_okay),
-        metadata.video.codec,
-        report_status(validation.video_okay),
-        metadata.container,
-        report_status(validation.container_okay),
-        metadata.video.pix_fmt,
-        report_status(validation.pix_fmt_okay),
-    );
-}
-
-fn report_status(is_okay: bool) -> &'static str {
-    if is_okay {
-        "✅"
-    } else {
-        "❌"
+fn handle_file(
+    path: &Path,
+    target: &Target,
+    fix_mode: FixMode,
+    mux_sidecars: bool,
+    sidecars: &mut SidecarIndex,
+    format: OutputFormat,
+) -> FileReport {
+    let mut file_report = FileReport::new(path, &target.name);
+    if let Err(err) = check_file(
+        path,
+        target,
+        fix_mode,
+        mux_sidecars,
+        sidecars,
+        format,
+        &mut file_report,
+    ) {
+        file_report.error = Some(format!("{:#}", err));
     }
+    file_report
 }
 
-fn reencode(
-    in_path: impl AsRef<Path>,
-    val: &FormatValidation,
-    default: &DefaultFormat,
+fn check_file(
+    path: &Path,
+    target: &Target,
+    fix_mode: FixMode,
+    mux_sidecars: bool,
+    sidecars: &mut SidecarIndex,
+    format: OutputFormat,
+    file_report: &mut FileReport,
 ) -> anyhow::Result<()> {
-    let vcodec = if val.video_okay {
-        "copy"
-    } else {
-        &default.video
-    };
-    let acodec = if val.audio_okay {
-        "copy"
-    } else {
-        &default.audio
-    };
-
-    let out_path = in_path.as_ref().with_extension("fixed.mkv");
-
-    // TODO: could let ffmepg prompt for this instead
-    if out_path.exists() {
-        bail!("fix target {} already exists", out_path.display());
+    file_report.sidecars = sidecars.find(path);
+    let metadata = file_report.metadata.insert(metadata::get_metadata(path)?);
+    let validation = file_report
+        .validation
+        .insert(validation::validate_format(metadata, &target.format_spec));
+    file_report.valid = Some(validation.is_valid());
+
+    if format == OutputFormat::Text {
+        report::print_file(path, validation, &file_report.sidecars);
     }
 
-    guard_terminal_size(100);
-
-    let mut cmd = Command::new("ffmpeg");
-    cmd.arg("-loglevel")
-        .arg("warning")
-        .arg("-stats")
-        .arg("-i")
-        .arg(in_path.as_ref())
-        .arg("-c:v")
-        .arg(vcodec);
-
-    if !val.pix_fmt_okay {
-        cmd.arg("-pix_fmt").arg(&default.pix_fmt);
+    if validation.is_valid() || fix_mode == FixMode::Off {
+        return Ok(());
     }
 
-    cmd.arg("-c:a").arg(acodec).arg(out_path);
-
-    debug!("{:?}", cmd);
+    // fix whatever can be fixed, reporting what can't alongside it
+    file_report.unfixable = validation.unfixable().map(|c| c.reason.clone()).collect();
+    if format == OutputFormat::Text && !file_report.unfixable.is_empty() {
+        report::print_unfixable(&file_report.unfixable);
+    }
+    if validation.fixable().next().is_none() {
+        return Ok(());
+    }
 
-    let mut ffmpeg = cmd.spawn()?;
+    let sidecars = if mux_sidecars {
+        file_report.sidecars.as_slice()
+    } else {
+        &[]
+    };
+    let plan = FixPlan::new(path, metadata, validation, sidecars, target)?;
+    let fix = file_report.fix.insert(FixReport {
+        output: plan.out_path.clone(),
+        status: FixStatus::Failed,
+        remux: plan.is_remux(),
+        command: plan.command_line(),
+        streams: plan.streams.clone(),
+    });
+
+    if fix_mode == FixMode::DryRun {
+        fix.status = FixStatus::Planned;
+        if format == OutputFormat::Text {
+            report::print_plan(fix);
+        }
+        return Ok(());
+    }
 
-    ffmpeg.wait()?;
+    fix::reencode(plan, metadata, target)?;
+    fix.status = FixStatus::Fixed;
 
     Ok(())
 }
 
-fn guard_terminal_size(min_width: u16) {
-    if let Some((Width(w), _)) = terminal_size() {
-        if w < min_width {
-            println!("Terminal width is below minimum size for nice ffmpeg output. Hit enter to continue.");
-            let _ = stdin().read_line(&mut String::new());
-        }
-    }
-}
-
 #[derive(Debug, Deserialize, Serialize)]
 struct Config {
     default_target: String,
@@ -233,14 +347,124 @@ struct Target {
     name: String,
     format_spec: FormatSpec,
     default: DefaultFormat,
+    #[serde(default)]
+    encode: EncodeOptions,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 struct FormatSpec {
     audio: Formats,
     video: Formats,
     container: Formats,
     pix_fmt: Formats,
+    #[serde(default)]
+    subtitle: Formats,
+    #[serde(default)]
+    max_width: Option<i64>,
+    #[serde(default)]
+    max_height: Option<i64>,
+    /// Frames per second
+    #[serde(default)]
+    max_frame_rate: Option<f64>,
+    /// Whether interlaced video is accepted, not checked when unset
+    #[serde(default)]
+    interlaced: Option<bool>,
+    /// Bits per second
+    #[serde(default)]
+    max_video_bitrate: Option<u64>,
+    #[serde(default)]
+    max_audio_channels: Option<i64>,
+    /// Only checked when configured, e.g. `Allow: ["mono", "stereo", "5.1"]`
+    #[serde(default)]
+    audio_channel_layout: Option<Formats>,
+    /// Rates in Hz, only checked when configured, e.g. `Allow: ["44100", "48000"]`
+    #[serde(default)]
+    audio_sample_rate: Option<Formats>,
+    /// What a fix does with audio streams the target can't play
+    #[serde(default)]
+    audio_strategy: AudioStrategy,
+    /// Dynamic range formats ("sdr", "hdr10", "hdr10+", "hlg" and "dolby-vision"), only
+    /// checked when configured
+    #[serde(default)]
+    hdr: Option<Formats>,
+    /// Dolby Vision profiles (e.g. "5" or "8"), only checked when configured
+    #[serde(default)]
+    dolby_vision_profile: Option<Formats>,
+    /// Video codec tags (e.g. "hvc1" or "avc1"), only checked for containers that use them
+    #[serde(default)]
+    video_codec_tag: Option<Formats>,
+    /// Profile and level rules for video streams, keyed by codec
+    #[serde(default)]
+    video_profiles: BTreeMap<String, VideoProfileSpec>,
+    /// Language and disposition rules for audio and subtitle streams, not checked when unset
+    #[serde(default)]
+    languages: Option<LanguageSpec>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct LanguageSpec {
+    /// Language tags as ffprobe reports them (ISO 639-2, e.g. "eng"), most preferred first; a
+    /// file needs an audio stream in one of them
+    audio: Vec<String>,
+    /// Which audio stream has to be flagged as the default
+    #[serde(default)]
+    default_audio: DefaultAudio,
+    /// Which audio streams a fix keeps and transcodes; when unset every audio stream is kept
+    /// and checked
+    #[serde(default)]
+    audio_selection: Option<AudioSelection>,
+    /// Whether subtitle streams titled as forced (e.g. "English (Forced)") have to be flagged
+    /// forced, so that players show them without being asked
+    #[serde(default)]
+    flag_forced_subtitles: bool,
+}
+
+/// Audio streams in the most preferred language the file has are always kept and checked, the
+/// rest depends on the variant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum AudioSelection {
+    /// Copy every other stream as is
+    KeepAll,
+    /// Drop every other stream
+    PreferredOnly,
+    /// Copy the original language stream (flagged original, or else the first audio stream) as
+    /// is and drop the rest
+    PreferredAndOriginal,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum DefaultAudio {
+    /// Any audio stream may be the default
+    #[default]
+    Any,
+    /// The default audio stream has to be in the most preferred language the file has
+    Preferred,
+    /// As `preferred`, and it also has to be the first audio stream, for players that ignore
+    /// the default flag
+    PreferredFirst,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum AudioStrategy {
+    /// Transcode incompatible streams in place
+    #[default]
+    Replace,
+    /// Keep incompatible streams for players that can use them and add a transcoded copy as
+    /// the default; a file is valid as long as one audio stream is compatible
+    AddCompat,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct VideoProfileSpec {
+    /// Profile names as ffprobe reports them, e.g. "High" or "Main 10"
+    #[serde(default)]
+    profile: Formats,
+    /// e.g. 4.1
+    #[serde(default)]
+    max_level: Option<f64>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -249,9 +473,56 @@ enum Formats {
     Reject(Vec<String>),
 }
 
+impl Default for Formats {
+    /// Accept everything
+    fn default() -> Self {
+        Formats::Reject(vec![])
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 struct DefaultFormat {
     audio: String,
     video: String,
     pix_fmt: String,
+    /// File extension (and so ffmpeg muxer) used when a file's container has to change
+    #[serde(default = "default_container")]
+    container: String,
+}
+
+fn default_container() -> String {
+    "mkv".to_string()
+}
+
+/// Encoder settings applied to streams a fix transcodes; anything left out is up to ffmpeg
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct EncodeOptions {
+    video_quality: Option<VideoQuality>,
+    preset: Option<String>,
+    tune: Option<String>,
+    profile: Option<String>,
+    level: Option<String>,
+    audio_bitrate: Option<String>,
+    /// ffmpeg audio filter used to downmix streams with too many channels, e.g. a `pan`
+    /// matrix; ffmpeg's default downmix is used when unset
+    downmix_filter: Option<String>,
+    /// tonemap filter algorithm used when converting HDR to SDR, "hable" when unset
+    tonemap: Option<String>,
+    /// ffmpeg filter used to deinterlace video, "bwdif=mode=send_frame" when unset (e.g.
+    /// "yadif"); it should output one frame per frame rather than per field, or the frame rate
+    /// doubles past the limits checked before fixing
+    deinterlace_filter: Option<String>,
+    /// ffmpeg scaler used when downscaling, e.g. "lanczos" or "bicubic"
+    scaler: Option<String>,
+    /// Passed to ffmpeg as-is, just before the output file
+    extra_args: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+enum VideoQuality {
+    /// Constant rate factor, e.g. 20 for libx264
+    Crf(u32),
+    /// Average bitrate, e.g. "4M"
+    Bitrate(String),
 }
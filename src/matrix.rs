@@ -0,0 +1,199 @@
+use itertools::Itertools;
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use crate::{
+    metadata,
+    report::{self, serialize_path, OutputFormat, Record, Reporter},
+    validation, Target,
+};
+
+/// Whether a single file can be played on each configured target
+#[derive(Debug, Serialize)]
+pub(crate) struct MatrixRow {
+    #[serde(serialize_with = "serialize_path")]
+    path: PathBuf,
+    targets: Vec<TargetResult>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct TargetResult {
+    target: String,
+    /// `None` if the file could not be checked
+    valid: Option<bool>,
+}
+
+impl Record for MatrixRow {
+    fn csv_row(&self) -> Vec<String> {
+        let mut row = vec![self.path.to_string_lossy().into_owned()];
+        row.extend(
+            self.targets
+                .iter()
+                .map(|t| t.valid.map(|v| v.to_string()).unwrap_or_default()),
+        );
+        row.push(self.error.clone().unwrap_or_default());
+        row
+    }
+}
+
+pub(crate) fn check_files(
+    targets: &[Target],
+    paths: &[PathBuf],
+    format: OutputFormat,
+) -> anyhow::Result<ExitCode> {
+    let csv_header = csv_header(targets);
+    if format == OutputFormat::Text {
+        println!("Checking {} against {} targets", paths.len(), targets.len());
+        println!();
+        println!(
+            "{}",
+            targets.iter().map(|t| pad(&t.name, cell_width(t))).join("")
+        );
+    }
+
+    let mut reporter = Reporter::new(format, &csv_header)?;
+    let mut errored = false;
+    for path in paths {
+        let row = check_file(path, targets);
+        if let Some(err) = &row.error {
+            errored = true;
+            eprintln!("error handling {}: {}", path.display(), err);
+        }
+        if format == OutputFormat::Text {
+            print_row(&row, targets);
+        }
+        reporter.write(&row)?;
+    }
+    reporter.finish()?;
+
+    Ok(if errored {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// The path, one column per target and the error, in the order of [`MatrixRow::csv_row`]
+fn csv_header(targets: &[Target]) -> Vec<&str> {
+    let mut header = vec!["path"];
+    header.extend(targets.iter().map(|t| t.name.as_str()));
+    header.push("error");
+    header
+}
+
+fn check_file(path: &Path, targets: &[Target]) -> MatrixRow {
+    let (metadata, error) = match metadata::get_metadata(path) {
+        Ok(metadata) => (Some(metadata), None),
+        Err(err) => (None, Some(format!("{:#}", err))),
+    };
+
+    MatrixRow {
+        path: path.to_path_buf(),
+        targets: targets
+            .iter()
+            .map(|target| TargetResult {
+                target: target.name.clone(),
+                valid: metadata
+                    .as_ref()
+                    .map(|m| validation::validate_format(m, &target.format_spec).is_valid()),
+            })
+            .collect(),
+        error,
+    }
+}
+
+fn print_row(row: &MatrixRow, targets: &[Target]) {
+    let name = row
+        .path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("..");
+
+    println!(
+        "{}{}",
+        row.targets
+            .iter()
+            .zip(targets)
+            .map(|(r, t)| match r.valid {
+                // the status emoji take up two columns in most terminals
+                Some(valid) => format!(
+                    "{}{}",
+                    report::report_status(valid),
+                    " ".repeat(cell_width(t) - 2)
+                ),
+                None => pad("?", cell_width(t)),
+            })
+            .join(""),
+        name
+    );
+}
+
+fn cell_width(target: &Target) -> usize {
+    target.name.chars().count().max(2) + 2
+}
+
+fn pad(value: &str, width: usize) -> String {
+    format!("{:<width$}", value, width = width)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DefaultFormat, EncodeOptions, FormatSpec};
+
+    fn mk_target(name: &str) -> Target {
+        Target {
+            name: name.to_string(),
+            format_spec: FormatSpec::default(),
+            default: DefaultFormat {
+                audio: "aac".to_string(),
+                video: "h264".to_string(),
+                pix_fmt: "yuv420p".to_string(),
+                container: "mp4".to_string(),
+            },
+            encode: EncodeOptions::default(),
+        }
+    }
+
+    #[test]
+    fn csv_row_matches_header() {
+        let targets = [mk_target("roku"), mk_target("sony")];
+        let row = MatrixRow {
+            path: PathBuf::from("/nonexistent/movie.mkv"),
+            targets: vec![
+                TargetResult {
+                    target: "roku".to_string(),
+                    valid: Some(true),
+                },
+                TargetResult {
+                    target: "sony".to_string(),
+                    valid: Some(false),
+                },
+            ],
+            error: None,
+        };
+
+        assert_eq!(csv_header(&targets), ["path", "roku", "sony", "error"]);
+        assert_eq!(
+            row.csv_row(),
+            ["/nonexistent/movie.mkv", "true", "false", ""]
+        );
+    }
+
+    #[test]
+    fn unprobed_file_has_no_results() {
+        let targets = [mk_target("roku"), mk_target("sony")];
+        let row = check_file(Path::new("/nonexistent/movie.mkv"), &targets);
+
+        assert!(row.error.is_some());
+        assert!(row.targets.iter().all(|t| t.valid.is_none()));
+        let csv = row.csv_row();
+        assert_eq!(csv.len(), csv_header(&targets).len());
+        assert_eq!(csv[1..3], ["", ""]);
+        assert_eq!(csv[3], row.error.unwrap());
+    }
+}
@@ -1,39 +1,203 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use ffprobe::{FfProbe, Stream};
 use itertools::Itertools;
-use log::debug;
-use std::path::Path;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, process::Command};
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub(crate) struct FileMetadata {
     pub(crate) container: String,
-    #[allow(unused)] // TODO: change to expect when available; for future functionality
+    /// Duration in minutes
     pub(crate) duration: Option<f64>,
-    pub(crate) video: VideoMetadata,
-    pub(crate) audio: AudioMetadata,
+    pub(crate) video: Vec<VideoMetadata>,
+    pub(crate) audio: Vec<AudioMetadata>,
+    pub(crate) subtitle: Vec<SubtitleMetadata>,
+    /// Streams that aren't checked against a target but should survive a fix, e.g. cover
+    /// art, fonts and data tracks
+    pub(crate) other: Vec<OtherMetadata>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub(crate) struct VideoMetadata {
-    #[allow(unused)] // TODO: change to expect when available; for future functionality
     pub(crate) index: i64,
     pub(crate) codec: String,
+    /// The MP4 style codec tag, e.g. "hvc1" or "hev1"; `None` for containers such as Matroska
+    /// that don't use them
+    pub(crate) codec_tag: Option<String>,
     pub(crate) pix_fmt: String,
+    /// Duration in minutes, from the stream itself or the tag Matroska muxers write
+    pub(crate) duration: Option<f64>,
+    pub(crate) width: Option<i64>,
+    pub(crate) height: Option<i64>,
+    /// Average frames per second
+    pub(crate) frame_rate: Option<f64>,
+    /// "progressive", or for interlaced video which field comes first, e.g. "tt" or "bb"
+    pub(crate) field_order: Option<String>,
+    /// Bits per second, from the statistics tags mkvmerge writes when the container doesn't
+    /// record a bitrate per stream, or else worked out from the whole file's bitrate when
+    /// every other stream's is known
+    pub(crate) bit_rate: Option<u64>,
+    pub(crate) profile: Option<String>,
+    /// In the form used by codec specifications, e.g. 4.1
+    pub(crate) level: Option<f64>,
+    pub(crate) color_transfer: Option<String>,
+    pub(crate) color_primaries: Option<String>,
+    pub(crate) color_space: Option<String>,
+    pub(crate) hdr: HdrFormat,
+    pub(crate) dolby_vision: Option<DolbyVision>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct DolbyVision {
+    pub(crate) profile: u8,
+    pub(crate) level: Option<u8>,
+    /// What players without Dolby Vision see, `None` when the base layer can't be shown on
+    /// its own (e.g. profile 5)
+    pub(crate) base_layer: Option<HdrFormat>,
+}
+
+/// The dynamic range of a video stream, worked out from its transfer characteristics and side
+/// data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) enum HdrFormat {
+    #[serde(rename = "sdr")]
+    Sdr,
+    #[serde(rename = "hdr10")]
+    Hdr10,
+    #[serde(rename = "hdr10+")]
+    Hdr10Plus,
+    #[serde(rename = "hlg")]
+    Hlg,
+    #[serde(rename = "dolby-vision")]
+    DolbyVision,
+}
+
+impl HdrFormat {
+    /// The name used in target rules
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            HdrFormat::Sdr => "sdr",
+            HdrFormat::Hdr10 => "hdr10",
+            HdrFormat::Hdr10Plus => "hdr10+",
+            HdrFormat::Hlg => "hlg",
+            HdrFormat::DolbyVision => "dolby-vision",
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub(crate) struct AudioMetadata {
-    #[allow(unused)] // TODO: change to expect when available; for future functionality
     pub(crate) index: i64,
     pub(crate) codec: String,
-    #[allow(unused)] // TODO: change to expect when available; for future functionality
     pub(crate) channels: i64,
+    /// e.g. "stereo" or "5.1(side)"
+    pub(crate) channel_layout: Option<String>,
+    /// In Hz, as reported by ffprobe
+    pub(crate) sample_rate: Option<String>,
+    /// ISO 639-2 language tag, e.g. "eng"; `None` when untagged or "und"
+    pub(crate) language: Option<String>,
+    pub(crate) disposition: Disposition,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct SubtitleMetadata {
+    pub(crate) index: i64,
+    pub(crate) codec: String,
+    /// ISO 639-2 language tag, e.g. "eng"; `None` when untagged or "und"
+    pub(crate) language: Option<String>,
+    pub(crate) title: Option<String>,
+    pub(crate) disposition: Disposition,
+}
+
+/// The disposition flags players use to pick streams, as far as videofix cares about them
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Disposition {
+    pub(crate) default: bool,
+    pub(crate) forced: bool,
+    pub(crate) original: bool,
+    pub(crate) dub: bool,
+    pub(crate) comment: bool,
+    pub(crate) hearing_impaired: bool,
+    pub(crate) visual_impaired: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct OtherMetadata {
+    pub(crate) index: i64,
+    pub(crate) codec_type: String,
+    pub(crate) codec: Option<String>,
+    pub(crate) attached_pic: bool,
+}
+
+/// Stream details ffprobe reports that the ffprobe crate doesn't expose, parsed from the same
+/// output
+#[derive(Debug, Default, Deserialize)]
+struct ExtraDetails {
+    #[serde(default)]
+    streams: Vec<ExtraStream>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ExtraStream {
+    index: i64,
+    color_transfer: Option<String>,
+    color_primaries: Option<String>,
+    #[serde(default)]
+    side_data_list: Vec<ExtraSideData>,
+    #[serde(default)]
+    tags: ExtraTags,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ExtraTags {
+    title: Option<String>,
+    /// Bits per second, as counted by mkvmerge
+    #[serde(rename = "BPS")]
+    bps: Option<String>,
+    /// e.g. "01:23:45.678000000"
+    #[serde(rename = "DURATION")]
+    duration: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ExtraSideData {
+    #[serde(default)]
+    side_data_type: String,
+    // from the DOVI configuration record
+    dv_profile: Option<u8>,
+    dv_level: Option<u8>,
+    dv_bl_signal_compatibility_id: Option<u8>,
+}
+
+/// Frames read by [`probe_first_frame`]
+#[derive(Debug, Default, Deserialize)]
+struct ExtraFrames {
+    #[serde(default)]
+    frames: Vec<ExtraFrame>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ExtraFrame {
+    #[serde(default)]
+    side_data_list: Vec<ExtraSideData>,
 }
 
 pub(crate) fn get_metadata(path: impl AsRef<Path>) -> anyhow::Result<FileMetadata> {
     debug!("calling ffprobe");
-    let details = ffprobe::ffprobe(&path)
+    let (details, mut extra) = probe(path.as_ref())
         .map_err(|err| anyhow!("ffprobe error in {}: {}", path.as_ref().display(), err))?;
+    for stream in extra.streams.iter_mut().filter(|s| may_be_hdr10_plus(s)) {
+        match probe_first_frame(path.as_ref(), stream.index) {
+            Ok(side_data) => stream.side_data_list.extend(side_data),
+            Err(err) => warn!(
+                "could not read the first frame of stream {} in {}: {}",
+                stream.index,
+                path.as_ref().display(),
+                err
+            ),
+        }
+    }
     debug!("ffprobe {:#?}", &details);
     let duration = details
         .format
@@ -46,10 +210,80 @@ pub(crate) fn get_metadata(path: impl AsRef<Path>) -> anyhow::Result<FileMetadat
         container: get_container(&details),
         duration,
         audio: get_audio_metadata(&details)?,
-        video: get_video_metadata(&details)?,
+        video: get_video_metadata(&details, &extra)?,
+        subtitle: get_subtitle_metadata(&details, &extra)?,
+        other: get_other_metadata(&details),
     })
 }
 
+/// HDR10+ metadata is carried by each frame rather than the stream, so PQ streams that aren't
+/// already known to be Dolby Vision need their first frame looked at
+fn may_be_hdr10_plus(stream: &ExtraStream) -> bool {
+    stream.color_transfer.as_deref() == Some("smpte2084")
+        && !stream
+            .side_data_list
+            .iter()
+            .any(|d| d.side_data_type == "DOVI configuration record")
+}
+
+/// Get the side data of the first frame of stream `index`
+fn probe_first_frame(path: &Path, index: i64) -> anyhow::Result<Vec<ExtraSideData>> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "quiet", "-select_streams"])
+        .arg(index.to_string())
+        .args([
+            "-read_intervals",
+            "%+#1",
+            "-show_frames",
+            "-print_format",
+            "json",
+        ])
+        .arg(path)
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "ffprobe exited with status code {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let frames: ExtraFrames = serde_json::from_slice(&output.stdout)?;
+    Ok(frames
+        .frames
+        .into_iter()
+        .next()
+        .map(|f| f.side_data_list)
+        .unwrap_or_default())
+}
+
+/// Run ffprobe the same way as [`ffprobe::ffprobe`], keeping the fields it drops
+fn probe(path: &Path) -> anyhow::Result<(FfProbe, ExtraDetails)> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "quiet",
+            "-show_format",
+            "-show_streams",
+            "-print_format",
+            "json",
+        ])
+        .arg(path)
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "ffprobe exited with status code {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok((
+        serde_json::from_slice(&output.stdout)?,
+        serde_json::from_slice(&output.stdout)?,
+    ))
+}
+
 fn get_container(details: &FfProbe) -> String {
     details
         .format
@@ -59,31 +293,130 @@ fn get_container(details: &FfProbe) -> String {
         .collect()
 }
 
-fn get_video_metadata(details: &FfProbe) -> anyhow::Result<VideoMetadata> {
-    let video_stream = find_stream_by_type(details, "video")?;
+fn get_video_metadata(
+    details: &FfProbe,
+    extra: &ExtraDetails,
+) -> anyhow::Result<Vec<VideoMetadata>> {
+    find_streams_by_type(details, "video")?
+        .into_iter()
+        .map(|video_stream| {
+            debug!("video {:#?}", video_stream);
+            let extra_stream = extra.streams.iter().find(|s| s.index == video_stream.index);
+            let color_transfer = extra_stream.and_then(|s| s.color_transfer.clone());
 
-    debug!("video {:#?}", video_stream);
+            Ok(VideoMetadata {
+                index: video_stream.index,
+                codec: get_codec(video_stream)?,
+                codec_tag: get_codec_tag(video_stream),
+                pix_fmt: get_pix_fmt(video_stream)?,
+                duration: get_stream_duration(video_stream, extra_stream),
+                width: video_stream.width,
+                height: video_stream.height,
+                frame_rate: parse_frame_rate(&video_stream.avg_frame_rate),
+                field_order: video_stream
+                    .field_order
+                    .clone()
+                    .filter(|order| order != "unknown"),
+                bit_rate: get_video_bit_rate(details, extra, video_stream),
+                profile: video_stream.profile.clone(),
+                level: video_stream
+                    .level
+                    .and_then(|level| normalize_level(&video_stream.codec_name, level)),
+                hdr: get_hdr_format(color_transfer.as_deref(), extra_stream),
+                dolby_vision: extra_stream.and_then(get_dolby_vision),
+                color_transfer,
+                color_primaries: extra_stream.and_then(|s| s.color_primaries.clone()),
+                color_space: video_stream.color_space.clone(),
+            })
+        })
+        .collect()
+}
 
-    Ok(VideoMetadata {
-        index: video_stream.index,
-        codec: get_codec(video_stream)?,
-        pix_fmt: get_pix_fmt(video_stream)?,
-    })
+fn get_audio_metadata(details: &FfProbe) -> anyhow::Result<Vec<AudioMetadata>> {
+    find_streams_by_type(details, "audio")?
+        .into_iter()
+        .map(|audio_stream| {
+            debug!("audio {:#?}", audio_stream);
+
+            Ok(AudioMetadata {
+                index: audio_stream.index,
+                codec: get_codec(audio_stream)?,
+                channels: audio_stream.channels.unwrap_or(0),
+                channel_layout: audio_stream.channel_layout.clone(),
+                sample_rate: audio_stream.sample_rate.clone(),
+                language: get_language(audio_stream),
+                disposition: get_disposition(audio_stream),
+            })
+        })
+        .collect()
 }
 
-fn get_audio_metadata(details: &FfProbe) -> anyhow::Result<AudioMetadata> {
-    let audio_stream = find_stream_by_type(details, "audio")?;
+fn get_subtitle_metadata(
+    details: &FfProbe,
+    extra: &ExtraDetails,
+) -> anyhow::Result<Vec<SubtitleMetadata>> {
+    // unlike audio and video, a file without any subtitles is perfectly normal
+    streams_by_type(details, "subtitle")
+        .into_iter()
+        .map(|subtitle_stream| {
+            debug!("subtitle {:#?}", subtitle_stream);
 
-    debug!("audio {:#?}", audio_stream);
+            Ok(SubtitleMetadata {
+                index: subtitle_stream.index,
+                codec: get_codec(subtitle_stream)?,
+                language: get_language(subtitle_stream),
+                title: extra
+                    .streams
+                    .iter()
+                    .find(|s| s.index == subtitle_stream.index)
+                    .and_then(|s| s.tags.title.clone()),
+                disposition: get_disposition(subtitle_stream),
+            })
+        })
+        .collect()
+}
 
-    Ok(AudioMetadata {
-        index: audio_stream.index,
-        codec: get_codec(audio_stream)?,
-        channels: audio_stream.channels.unwrap_or(0),
-    })
+fn get_other_metadata(details: &FfProbe) -> Vec<OtherMetadata> {
+    details
+        .streams
+        .iter()
+        .filter(|s| {
+            s.disposition.attached_pic != 0
+                || !matches!(
+                    s.codec_type.as_deref(),
+                    Some("video") | Some("audio") | Some("subtitle")
+                )
+        })
+        .map(|s| OtherMetadata {
+            index: s.index,
+            codec_type: s
+                .codec_type
+                .clone()
+                .unwrap_or_else(|| "unknown".to_string()),
+            codec: s.codec_name.clone(),
+            attached_pic: s.disposition.attached_pic != 0,
+        })
+        .collect()
+}
+
+fn find_streams_by_type<'a>(
+    details: &'a FfProbe,
+    stream_type: &str,
+) -> anyhow::Result<Vec<&'a Stream>> {
+    let streams = streams_by_type(details, stream_type);
+
+    if streams.is_empty() {
+        bail!(
+            "no {} stream found in {}",
+            stream_type,
+            details.format.filename
+        );
+    }
+
+    Ok(streams)
 }
 
-fn find_stream_by_type<'a>(details: &'a FfProbe, stream_type: &str) -> anyhow::Result<&'a Stream> {
+fn streams_by_type<'a>(details: &'a FfProbe, stream_type: &str) -> Vec<&'a Stream> {
     details
         .streams
         .iter()
@@ -93,23 +426,9 @@ fn find_stream_by_type<'a>(details: &'a FfProbe, stream_type: &str) -> anyhow::R
                 .map(|s| s == stream_type)
                 .unwrap_or_else(|| false)
         })
-        .at_most_one()
-        .map_err(|_| {
-            anyhow!(
-                "more than one matching {} stream in {}",
-                stream_type,
-                details.format.filename
-            )
-        })
-        .and_then(|maybe_stream| {
-            maybe_stream.ok_or_else(|| {
-                anyhow!(
-                    "no {} stream found in {}",
-                    stream_type,
-                    details.format.filename
-                )
-            })
-        })
+        // cover art is exposed as a video stream but is never played back
+        .filter(|s| s.disposition.attached_pic == 0)
+        .collect_vec()
 }
 
 fn get_codec(stream: &Stream) -> anyhow::Result<String> {
@@ -120,6 +439,32 @@ fn get_codec(stream: &Stream) -> anyhow::Result<String> {
         .ok_or_else(|| anyhow!("no codec found for stream {}", stream.index))
 }
 
+fn get_codec_tag(stream: &Stream) -> Option<String> {
+    // ffprobe shows a missing tag as its bytes, "[0][0][0][0]"
+    Some(stream.codec_tag_string.clone()).filter(|t| !t.is_empty() && !t.starts_with('['))
+}
+
+fn get_language(stream: &Stream) -> Option<String> {
+    stream
+        .tags
+        .as_ref()
+        .and_then(|t| t.language.clone())
+        .filter(|l| !l.is_empty() && l != "und")
+}
+
+fn get_disposition(stream: &Stream) -> Disposition {
+    let d = &stream.disposition;
+    Disposition {
+        default: d.default != 0,
+        forced: d.forced != 0,
+        original: d.original != 0,
+        dub: d.dub != 0,
+        comment: d.comment != 0,
+        hearing_impaired: d.hearing_impaired != 0,
+        visual_impaired: d.visual_impaired != 0,
+    }
+}
+
 fn get_pix_fmt(stream: &Stream) -> anyhow::Result<String> {
     stream
         .pix_fmt
@@ -127,3 +472,273 @@ fn get_pix_fmt(stream: &Stream) -> anyhow::Result<String> {
         .map(|s| s.to_string())
         .ok_or_else(|| anyhow!("no pix_fmt found for stream {}", stream.index))
 }
+
+/// Parse ffprobe's rational frame rates such as `24000/1001`; `0/0` means unknown
+fn parse_frame_rate(rate: &str) -> Option<f64> {
+    let (num, den) = rate.split_once('/').unwrap_or((rate, "1"));
+    let num = num.parse::<f64>().ok()?;
+    let den = den.parse::<f64>().ok()?;
+    if num == 0.0 || den == 0.0 {
+        return None;
+    }
+    Some(num / den)
+}
+
+fn parse_bit_rate(bit_rate: &Option<String>) -> Option<u64> {
+    bit_rate.as_ref().and_then(|b| b.parse::<u64>().ok())
+}
+
+fn get_stream_duration(stream: &Stream, extra: Option<&ExtraStream>) -> Option<f64> {
+    let seconds = stream
+        .duration
+        .as_ref()
+        .and_then(|d| d.parse::<f64>().ok())
+        .or_else(|| parse_duration_tag(extra?.tags.duration.as_deref()?))?;
+    Some(seconds / 60.0)
+}
+
+/// Parse a duration of the form "HH:MM:SS.fraction" into seconds
+fn parse_duration_tag(tag: &str) -> Option<f64> {
+    let mut parts = tag.splitn(3, ':');
+    let hours = parts.next()?.parse::<f64>().ok()?;
+    let minutes = parts.next()?.parse::<f64>().ok()?;
+    let seconds = parts.next()?.parse::<f64>().ok()?;
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+fn stream_bit_rate(stream: &Stream, extra: &ExtraDetails) -> Option<u64> {
+    parse_bit_rate(&stream.bit_rate).or_else(|| {
+        extra
+            .streams
+            .iter()
+            .find(|s| s.index == stream.index)
+            .and_then(|s| parse_bit_rate(&s.tags.bps))
+    })
+}
+
+fn get_video_bit_rate(details: &FfProbe, extra: &ExtraDetails, video: &Stream) -> Option<u64> {
+    stream_bit_rate(video, extra).or_else(|| {
+        // the whole file's bitrate includes every audio track (a TrueHD one can be as big
+        // as the video), so it is only any use once those are taken out; subtitles and
+        // the like are small enough to ignore
+        let total = parse_bit_rate(&details.format.bit_rate)?;
+        let others = details
+            .streams
+            .iter()
+            .filter(|s| s.index != video.index && s.disposition.attached_pic == 0)
+            .filter(|s| matches!(s.codec_type.as_deref(), Some("video") | Some("audio")))
+            .map(|s| stream_bit_rate(s, extra))
+            .sum::<Option<u64>>()?;
+        total.checked_sub(others)
+    })
+}
+
+fn get_hdr_format(color_transfer: Option<&str>, stream: Option<&ExtraStream>) -> HdrFormat {
+    let has_side_data = |name: &str| {
+        stream.is_some_and(|s| {
+            s.side_data_list
+                .iter()
+                .any(|d| d.side_data_type.contains(name))
+        })
+    };
+
+    // Dolby Vision and HDR10+ are layered on top of PQ (or occasionally HLG) so they win
+    if has_side_data("DOVI configuration record") {
+        HdrFormat::DolbyVision
+    } else if has_side_data("HDR10+") {
+        HdrFormat::Hdr10Plus
+    } else {
+        match color_transfer {
+            Some("smpte2084") => HdrFormat::Hdr10,
+            Some("arib-std-b67") => HdrFormat::Hlg,
+            _ => HdrFormat::Sdr,
+        }
+    }
+}
+
+fn get_dolby_vision(stream: &ExtraStream) -> Option<DolbyVision> {
+    let record = stream
+        .side_data_list
+        .iter()
+        .find(|d| d.side_data_type == "DOVI configuration record")?;
+
+    Some(DolbyVision {
+        profile: record.dv_profile?,
+        level: record.dv_level,
+        // base layer signal compatibility ids from the Dolby Vision streams specification
+        base_layer: match record.dv_bl_signal_compatibility_id {
+            Some(1) | Some(6) => Some(HdrFormat::Hdr10),
+            Some(2) => Some(HdrFormat::Sdr),
+            Some(4) => Some(HdrFormat::Hlg),
+            _ => None,
+        },
+    })
+}
+
+/// ffprobe reports levels as integers: ten times the level for h264 and thirty times for hevc
+fn normalize_level(codec: &Option<String>, level: i64) -> Option<f64> {
+    // negative levels (usually -99) mean unknown
+    if level < 0 {
+        return None;
+    }
+    let level = level as f64;
+    Some(match codec.as_deref() {
+        Some("h264") => level / 10.0,
+        Some("hevc") => level / 30.0,
+        _ => level,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frame_rate_parses_rationals() {
+        let rate = parse_frame_rate("24000/1001").unwrap();
+        assert!((rate - 23.976).abs() < 0.001);
+        assert_eq!(parse_frame_rate("25/1"), Some(25.0));
+        assert_eq!(parse_frame_rate("0/0"), None);
+        assert_eq!(parse_frame_rate("garbage"), None);
+    }
+
+    #[test]
+    fn levels_are_normalized() {
+        let codec = |c: &str| Some(c.to_string());
+        assert_eq!(normalize_level(&codec("h264"), 41), Some(4.1));
+        assert_eq!(normalize_level(&codec("hevc"), 153), Some(5.1));
+        assert_eq!(normalize_level(&codec("h264"), -99), None);
+    }
+
+    #[test]
+    fn hdr_format_from_transfer_and_side_data() {
+        assert_eq!(get_hdr_format(Some("bt709"), None), HdrFormat::Sdr);
+        assert_eq!(get_hdr_format(None, None), HdrFormat::Sdr);
+        assert_eq!(get_hdr_format(Some("smpte2084"), None), HdrFormat::Hdr10);
+        assert_eq!(get_hdr_format(Some("arib-std-b67"), None), HdrFormat::Hlg);
+
+        let extra: ExtraDetails = serde_json::from_str(
+            r#"{"streams": [{"index": 0, "color_transfer": "smpte2084",
+                "side_data_list": [{"side_data_type": "DOVI configuration record"}]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            get_hdr_format(Some("smpte2084"), extra.streams.first()),
+            HdrFormat::DolbyVision
+        );
+        assert!(!may_be_hdr10_plus(&extra.streams[0]));
+    }
+
+    #[test]
+    fn hdr10_plus_from_first_frame() {
+        let mut extra: ExtraDetails = serde_json::from_str(
+            r#"{"streams": [{"index": 0, "color_transfer": "smpte2084",
+                "side_data_list": [{"side_data_type": "Mastering display metadata"}]}]}"#,
+        )
+        .unwrap();
+        let stream = &mut extra.streams[0];
+        assert!(may_be_hdr10_plus(stream));
+        assert_eq!(
+            get_hdr_format(Some("smpte2084"), Some(stream)),
+            HdrFormat::Hdr10
+        );
+
+        let frames: ExtraFrames = serde_json::from_str(
+            r#"{"frames": [{"side_data_list": [
+                {"side_data_type": "Mastering display metadata"},
+                {"side_data_type": "HDR Dynamic Metadata SMPTE2094-40 (HDR10+)",
+                    "application_version": 1, "num_windows": 1}
+            ]}]}"#,
+        )
+        .unwrap();
+        stream
+            .side_data_list
+            .extend(frames.frames.into_iter().flat_map(|f| f.side_data_list));
+        assert_eq!(
+            get_hdr_format(Some("smpte2084"), Some(stream)),
+            HdrFormat::Hdr10Plus
+        );
+    }
+
+    #[test]
+    fn dolby_vision_profiles() {
+        let extra: ExtraDetails = serde_json::from_str(
+            r#"{"streams": [
+                {"index": 0, "side_data_list": [{"side_data_type": "DOVI configuration record",
+                    "dv_profile": 8, "dv_level": 6, "dv_bl_signal_compatibility_id": 1}]},
+                {"index": 1, "side_data_list": [{"side_data_type": "DOVI configuration record",
+                    "dv_profile": 5, "dv_level": 6, "dv_bl_signal_compatibility_id": 0}]},
+                {"index": 2}
+            ]}"#,
+        )
+        .unwrap();
+
+        let profile_8 = get_dolby_vision(&extra.streams[0]).unwrap();
+        assert_eq!(profile_8.profile, 8);
+        assert_eq!(profile_8.base_layer, Some(HdrFormat::Hdr10));
+        let profile_5 = get_dolby_vision(&extra.streams[1]).unwrap();
+        assert_eq!(profile_5.base_layer, None);
+        assert!(get_dolby_vision(&extra.streams[2]).is_none());
+    }
+
+    #[test]
+    fn video_bit_rate_leaves_out_audio() {
+        let stream = |index, codec_type: &str, bit_rate: Option<&str>| Stream {
+            index,
+            codec_type: Some(codec_type.to_string()),
+            bit_rate: bit_rate.map(|b| b.to_string()),
+            ..Stream::default()
+        };
+        let mut details = FfProbe {
+            streams: vec![
+                stream(0, "video", None),
+                stream(1, "audio", Some("4000000")),
+                stream(2, "subtitle", None),
+            ],
+            ..FfProbe::default()
+        };
+        details.format.bit_rate = Some("11000000".to_string());
+        let no_extra = ExtraDetails::default();
+
+        assert_eq!(
+            get_video_bit_rate(&details, &no_extra, &details.streams[0]),
+            Some(7_000_000)
+        );
+
+        // an audio track of unknown size makes the video's unknown too
+        details.streams[1].bit_rate = None;
+        assert_eq!(
+            get_video_bit_rate(&details, &no_extra, &details.streams[0]),
+            None
+        );
+
+        // unless mkvmerge counted it
+        let extra: ExtraDetails =
+            serde_json::from_str(r#"{"streams": [{"index": 0, "tags": {"BPS": "6500000"}}]}"#)
+                .unwrap();
+        assert_eq!(
+            get_video_bit_rate(&details, &extra, &details.streams[0]),
+            Some(6_500_000)
+        );
+    }
+
+    #[test]
+    fn stream_duration_from_matroska_tag() {
+        let extra: ExtraDetails = serde_json::from_str(
+            r#"{"streams": [{"index": 0, "tags": {"DURATION": "01:30:03.000000000"}}]}"#,
+        )
+        .unwrap();
+        let stream = Stream::default();
+
+        assert_eq!(
+            get_stream_duration(&stream, extra.streams.first()),
+            Some(90.05)
+        );
+        let stream = Stream {
+            duration: Some("120.0".to_string()),
+            ..Stream::default()
+        };
+        assert_eq!(get_stream_duration(&stream, None), Some(2.0));
+        assert_eq!(get_stream_duration(&Stream::default(), None), None);
+    }
+}
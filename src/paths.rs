@@ -0,0 +1,481 @@
+use anyhow::Context;
+use globset::{Glob, GlobMatcher};
+use log::{debug, warn};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+use crate::report::serialize_path;
+
+const VALID_EXTENSIONS: [&str; 6] = ["mkv", "mp4", "avi", "webm", "mov", "wmv"];
+
+/// Subtitle file extensions and the codec ffmpeg reads them as
+const SUBTITLE_EXTENSIONS: [(&str, &str); 5] = [
+    ("srt", "subrip"),
+    ("ass", "ass"),
+    ("ssa", "ssa"),
+    ("vtt", "webvtt"),
+    ("sup", "hdmv_pgs_subtitle"),
+];
+
+/// Two letter (ISO 639-1) language codes as commonly used in sidecar names, with the three
+/// letter (ISO 639-2/B) codes containers use
+const LANGUAGE_CODES: [(&str, &str); 28] = [
+    ("ar", "ara"),
+    ("cs", "cze"),
+    ("da", "dan"),
+    ("de", "ger"),
+    ("el", "gre"),
+    ("en", "eng"),
+    ("es", "spa"),
+    ("fi", "fin"),
+    ("fr", "fre"),
+    ("he", "heb"),
+    ("hi", "hin"),
+    ("hu", "hun"),
+    ("id", "ind"),
+    ("it", "ita"),
+    ("ja", "jpn"),
+    ("ko", "kor"),
+    ("nl", "dut"),
+    ("no", "nor"),
+    ("pl", "pol"),
+    ("pt", "por"),
+    ("ro", "rum"),
+    ("ru", "rus"),
+    ("sv", "swe"),
+    ("th", "tha"),
+    ("tr", "tur"),
+    ("uk", "ukr"),
+    ("vi", "vie"),
+    ("zh", "chi"),
+];
+
+/// A subtitle file beside a video and named after it, e.g. `Movie.en.forced.srt` for
+/// `Movie.mkv`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Sidecar {
+    #[serde(serialize_with = "serialize_path")]
+    pub(crate) path: PathBuf,
+    pub(crate) codec: String,
+    /// ISO 639-2 language tag, e.g. "eng"
+    pub(crate) language: Option<String>,
+    pub(crate) forced: bool,
+    /// Subtitles for the deaf and hard of hearing, named `.sdh` or `.cc`
+    pub(crate) hearing_impaired: bool,
+}
+
+/// Controls which files are picked up when a directory is scanned
+#[derive(Debug, Default)]
+pub(crate) struct ScanOptions {
+    pub(crate) recursive: bool,
+    /// Deepest level to descend to when recursive, where files directly inside the scanned
+    /// directory are at depth 1
+    pub(crate) max_depth: Option<usize>,
+    pub(crate) follow_symlinks: bool,
+    pub(crate) include: Vec<Pattern>,
+    pub(crate) exclude: Vec<Pattern>,
+}
+
+impl ScanOptions {
+    fn is_included(&self, relative: &Path) -> bool {
+        self.include.is_empty() || self.include.iter().any(|p| p.matches(relative, false))
+    }
+
+    fn is_excluded(&self, relative: &Path, is_dir: bool) -> bool {
+        self.exclude.iter().any(|p| p.matches(relative, is_dir))
+    }
+}
+
+/// A glob matched against paths relative to the scanned directory.
+///
+/// Patterns without a `/` (e.g. `*.fixed.mkv`) are matched against the file or directory
+/// name at any depth, otherwise against the whole relative path. A trailing `/` (e.g.
+/// `Extras/`) only matches directories.
+#[derive(Debug)]
+pub(crate) struct Pattern {
+    matcher: GlobMatcher,
+    match_name: bool,
+    dir_only: bool,
+}
+
+impl Pattern {
+    pub(crate) fn new(pattern: &str) -> anyhow::Result<Self> {
+        let dir_only = pattern.ends_with('/');
+        let trimmed = pattern.trim_end_matches('/');
+        let matcher = Glob::new(trimmed)
+            .with_context(|| format!("invalid glob pattern \"{}\"", pattern))?
+            .compile_matcher();
+
+        Ok(Pattern {
+            matcher,
+            match_name: !trimmed.contains('/'),
+            dir_only,
+        })
+    }
+
+    fn matches(&self, relative: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.match_name {
+            relative
+                .file_name()
+                .map(|name| self.matcher.is_match(name))
+                .unwrap_or(false)
+        } else {
+            self.matcher.is_match(relative)
+        }
+    }
+}
+
+pub(crate) fn get_paths(
+    check_path: &Path,
+    options: &ScanOptions,
+    check_paths: &mut Vec<PathBuf>,
+    sidecars: &mut SidecarIndex,
+) -> anyhow::Result<()> {
+    let max_depth = if options.recursive {
+        options.max_depth.unwrap_or(usize::MAX)
+    } else {
+        1
+    };
+
+    let walker = WalkDir::new(check_path)
+        .min_depth(1)
+        .max_depth(max_depth)
+        .follow_links(options.follow_symlinks)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            let relative = relative_path(check_path, entry.path());
+            let excluded = options.is_excluded(relative, entry.file_type().is_dir());
+            if excluded {
+                debug!("excluding {}", entry.path().display());
+            }
+            !excluded
+        });
+
+    let extensions = VALID_EXTENSIONS.map(OsStr::new);
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) if err.depth() == 0 => {
+                return Err(err).with_context(|| format!("could not read {}", check_path.display()))
+            }
+            Err(err) => {
+                warn!("skipping: {}", err);
+                continue;
+            }
+        };
+
+        let path = entry.path();
+        if path.is_file() {
+            sidecars.add(path);
+        }
+        if path.is_file() && options.is_included(relative_path(check_path, path)) {
+            if let Some(extension) = path.extension() {
+                if extensions.contains(&extension) {
+                    check_paths.push(path.to_path_buf());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Subtitle files by directory, gathered while scanning so that each directory is only read
+/// once however many videos it holds
+#[derive(Debug, Default)]
+pub(crate) struct SidecarIndex {
+    dirs: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl SidecarIndex {
+    /// Note a file found while scanning, which also marks its directory as read
+    fn add(&mut self, path: &Path) {
+        let files = self.dirs.entry(parent_dir(path).to_path_buf()).or_default();
+        if is_subtitle(path) {
+            files.push(path.to_path_buf());
+        }
+    }
+
+    /// Find the sidecar subtitle files that belong to `video`, sorted by name.
+    ///
+    /// Directories that weren't scanned (e.g. for files given directly) are read on first use; a
+    /// directory that can't be read is warned about and treated as having no sidecars.
+    pub(crate) fn find(&mut self, video: &Path) -> Vec<Sidecar> {
+        let Some(stem) = video.file_stem() else {
+            return Vec::new();
+        };
+        let dir = parent_dir(video);
+        let files = self
+            .dirs
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| read_subtitles(dir));
+        files
+            .iter()
+            .filter_map(|path| parse_sidecar(stem, path))
+            .inspect(|sidecar| debug!("found sidecar {}", sidecar.path.display()))
+            .collect()
+    }
+}
+
+fn read_subtitles(dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("could not read sidecars in {}: {}", dir.display(), err);
+            return Vec::new();
+        }
+    };
+    let mut files: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && is_subtitle(path))
+        .collect();
+    files.sort();
+    files
+}
+
+fn is_subtitle(path: &Path) -> bool {
+    path.extension().and_then(OsStr::to_str).is_some_and(|ext| {
+        SUBTITLE_EXTENSIONS
+            .iter()
+            .any(|(e, _)| e.eq_ignore_ascii_case(ext))
+    })
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Parse a sidecar name of the form `<stem>[.<language>][.forced][.sdh].<extension>`; names
+/// with anything else between the stem and extension belong to some other video (e.g.
+/// `Movie.Part2.en.srt` isn't a sidecar of `Movie.mkv`)
+fn parse_sidecar(video_stem: &OsStr, path: &Path) -> Option<Sidecar> {
+    let name = path.file_name()?.to_str()?;
+    let rest = name.strip_prefix(video_stem.to_str()?)?.strip_prefix('.')?;
+    let (tags, extension) = match rest.rsplit_once('.') {
+        Some((tags, extension)) => (Some(tags), extension),
+        None => (None, rest),
+    };
+    let extension = extension.to_lowercase();
+    let (_, codec) = SUBTITLE_EXTENSIONS
+        .iter()
+        .find(|(known, _)| *known == extension)?;
+
+    let mut sidecar = Sidecar {
+        path: path.to_path_buf(),
+        codec: codec.to_string(),
+        language: None,
+        forced: false,
+        hearing_impaired: false,
+    };
+    for tag in tags.into_iter().flat_map(|t| t.split('.')) {
+        let tag = tag.to_lowercase();
+        match tag.as_str() {
+            "forced" => sidecar.forced = true,
+            "sdh" | "cc" => sidecar.hearing_impaired = true,
+            _ if sidecar.language.is_none() => sidecar.language = Some(language_code(&tag)?),
+            _ => return None,
+        }
+    }
+    Some(sidecar)
+}
+
+fn language_code(tag: &str) -> Option<String> {
+    if !tag.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    match tag.len() {
+        2 => LANGUAGE_CODES
+            .iter()
+            .find(|(short, _)| *short == tag)
+            .map(|(_, long)| long.to_string()),
+        3 => Some(tag.to_string()),
+        _ => None,
+    }
+}
+
+/// Read a list of paths from `list_path` (or stdin when it is `-`).
+///
+/// Entries are NUL separated if the list contains any NUL characters (as produced by
+/// `find -print0`) and newline separated otherwise.
+pub(crate) fn read_path_list(list_path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut bytes = Vec::new();
+    if list_path == Path::new("-") {
+        io::stdin()
+            .read_to_end(&mut bytes)
+            .context("could not read path list from stdin")?;
+    } else {
+        File::open(list_path)
+            .and_then(|mut f| f.read_to_end(&mut bytes))
+            .with_context(|| format!("could not read path list {}", list_path.display()))?;
+    }
+    Ok(parse_path_list(&bytes))
+}
+
+fn parse_path_list(bytes: &[u8]) -> Vec<PathBuf> {
+    let separator = if bytes.contains(&b'\0') { b'\0' } else { b'\n' };
+    bytes
+        .split(|&b| b == separator)
+        .map(|entry| {
+            if separator == b'\n' {
+                entry.strip_suffix(b"\r").unwrap_or(entry)
+            } else {
+                entry
+            }
+        })
+        .filter(|entry| !entry.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Remove paths that refer to the same file, keeping the first occurrence
+pub(crate) fn dedup_paths(paths: &mut Vec<PathBuf>) {
+    let mut seen = HashSet::new();
+    paths.retain(|path| {
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        let first = seen.insert(canonical);
+        if !first {
+            debug!("skipping duplicate {}", path.display());
+        }
+        first
+    });
+}
+
+fn relative_path<'a>(root: &Path, path: &'a Path) -> &'a Path {
+    path.strip_prefix(root).unwrap_or(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pattern_name_matches_at_any_depth() {
+        let pattern = Pattern::new("*.fixed.mkv").unwrap();
+
+        assert!(pattern.matches(Path::new("movie.fixed.mkv"), false));
+        assert!(pattern.matches(Path::new("Show/Season 1/ep1.fixed.mkv"), false));
+        assert!(!pattern.matches(Path::new("Show/Season 1/ep1.mkv"), false));
+    }
+
+    #[test]
+    fn pattern_trailing_slash_only_matches_directories() {
+        let pattern = Pattern::new("Extras/").unwrap();
+
+        assert!(pattern.matches(Path::new("Show/Extras"), true));
+        assert!(!pattern.matches(Path::new("Show/Extras"), false));
+    }
+
+    #[test]
+    fn pattern_with_slash_matches_relative_path() {
+        let pattern = Pattern::new("Show/*/ep1.mkv").unwrap();
+
+        assert!(pattern.matches(Path::new("Show/Season 1/ep1.mkv"), false));
+        assert!(!pattern.matches(Path::new("Other/Season 1/ep1.mkv"), false));
+        assert!(!pattern.matches(Path::new("ep1.mkv"), false));
+    }
+
+    #[test]
+    fn path_list_newline_separated() {
+        let paths = parse_path_list(b"a.mkv\nShow/b c.mkv\r\n\n");
+
+        assert_eq!(
+            paths,
+            vec![PathBuf::from("a.mkv"), PathBuf::from("Show/b c.mkv")]
+        );
+    }
+
+    #[test]
+    fn path_list_nul_separated() {
+        let paths = parse_path_list(b"a.mkv\0odd\nname.mkv\0");
+
+        assert_eq!(
+            paths,
+            vec![PathBuf::from("a.mkv"), PathBuf::from("odd\nname.mkv")]
+        );
+    }
+
+    #[test]
+    fn include_defaults_to_everything() {
+        let mut options = ScanOptions::default();
+        assert!(options.is_included(Path::new("movie.mkv")));
+
+        options.include.push(Pattern::new("*.mkv").unwrap());
+        assert!(options.is_included(Path::new("movie.mkv")));
+        assert!(!options.is_included(Path::new("movie.mp4")));
+    }
+
+    #[test]
+    fn sidecar_names_are_parsed() {
+        let stem = OsStr::new("Movie");
+        let sidecar = |name: &str| parse_sidecar(stem, &Path::new("/media").join(name));
+
+        let forced = sidecar("Movie.en.forced.srt").unwrap();
+        assert_eq!(forced.codec, "subrip");
+        assert_eq!(forced.language.as_deref(), Some("eng"));
+        assert!(forced.forced);
+        assert!(!forced.hearing_impaired);
+
+        let sdh = sidecar("Movie.spa.SDH.ass").unwrap();
+        assert_eq!(sdh.language.as_deref(), Some("spa"));
+        assert!(sdh.hearing_impaired);
+
+        assert_eq!(sidecar("Movie.srt").unwrap().language, None);
+        // other videos' subtitles and other files
+        assert!(sidecar("Movie.Part2.en.srt").is_none());
+        assert!(sidecar("Movie 2.en.srt").is_none());
+        assert!(sidecar("Movie.en.nfo").is_none());
+        assert!(sidecar("Movie.mkv").is_none());
+    }
+
+    #[test]
+    fn sidecars_come_from_the_scanned_directory() {
+        let mut index = SidecarIndex::default();
+        for name in [
+            "Movie.mkv",
+            "Movie.en.srt",
+            "Movie.fr.forced.srt",
+            "Other.en.srt",
+        ] {
+            index.add(&Path::new("/nonexistent/media").join(name));
+        }
+
+        // the directory was scanned, so it isn't read again
+        let sidecars = index.find(Path::new("/nonexistent/media/Movie.mkv"));
+        let paths: Vec<_> = sidecars.iter().map(|s| s.path.as_path()).collect();
+        assert_eq!(
+            paths,
+            [
+                Path::new("/nonexistent/media/Movie.en.srt"),
+                Path::new("/nonexistent/media/Movie.fr.forced.srt")
+            ]
+        );
+        // a directory that can't be read has no sidecars rather than failing the file
+        assert!(index
+            .find(Path::new("/nonexistent/other/Movie.mkv"))
+            .is_empty());
+    }
+}
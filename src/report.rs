@@ -0,0 +1,545 @@
+use clap::ValueEnum;
+use itertools::Itertools;
+use serde::{Serialize, Serializer};
+use std::{
+    io::{self, Stdout, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    fix::{StreamAction, StreamPlan},
+    metadata::FileMetadata,
+    paths::Sidecar,
+    validation::{AudioValidation, ComponentValidation, FormatValidation, Selection},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// Human readable lines as each file is checked
+    Text,
+    /// A JSON array of file reports
+    Json,
+    /// One JSON file report per line, written as each file is checked
+    Ndjson,
+    /// One CSV row per file, with multiple streams joined by `;`
+    Csv,
+}
+
+/// Everything learned about a single file; this is the schema of the JSON output formats
+#[derive(Debug, Serialize)]
+pub(crate) struct FileReport {
+    #[serde(serialize_with = "serialize_path")]
+    pub(crate) path: PathBuf,
+    pub(crate) target: String,
+    /// Whether the file is valid for the target, `None` if it could not be checked
+    pub(crate) valid: Option<bool>,
+    pub(crate) metadata: Option<FileMetadata>,
+    pub(crate) validation: Option<FormatValidation>,
+    /// Subtitle files found beside the file
+    pub(crate) sidecars: Vec<Sidecar>,
+    /// Why no fix was attempted, when the file fails checks that no fix can correct
+    pub(crate) unfixable: Vec<String>,
+    /// Present when a fix was attempted
+    pub(crate) fix: Option<FixReport>,
+    pub(crate) error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct FixReport {
+    #[serde(serialize_with = "serialize_path")]
+    pub(crate) output: PathBuf,
+    pub(crate) status: FixStatus,
+    /// Whether every stream is copied as-is into a new container
+    pub(crate) remux: bool,
+    /// The ffmpeg command line, shell quoted
+    pub(crate) command: String,
+    pub(crate) streams: Vec<StreamPlan>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FixStatus {
+    Fixed,
+    Failed,
+    /// Only worked out because of `--dry-run`
+    Planned,
+}
+
+impl FixStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FixStatus::Fixed => "fixed",
+            FixStatus::Failed => "failed",
+            FixStatus::Planned => "planned",
+        }
+    }
+}
+
+impl FileReport {
+    pub(crate) fn new(path: &Path, target: &str) -> Self {
+        FileReport {
+            path: path.to_path_buf(),
+            target: target.to_string(),
+            valid: None,
+            metadata: None,
+            validation: None,
+            sidecars: Vec::new(),
+            unfixable: Vec::new(),
+            fix: None,
+            error: None,
+        }
+    }
+}
+
+pub(crate) fn serialize_path<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+pub(crate) fn serialize_optional_path<S: Serializer>(
+    path: &Option<PathBuf>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match path {
+        Some(path) => serialize_path(path, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// A single entry of machine readable output
+pub(crate) trait Record: Serialize {
+    fn csv_row(&self) -> Vec<String>;
+}
+
+impl Record for FileReport {
+    fn csv_row(&self) -> Vec<String> {
+        csv_row(self).to_vec()
+    }
+}
+
+/// Writes records in one of the machine readable formats; text output is printed directly
+/// (e.g. by [`print_file`]) while each file is handled
+pub(crate) struct Reporter<W: Write = Stdout> {
+    format: OutputFormat,
+    written: usize,
+    out: W,
+}
+
+impl Reporter {
+    pub(crate) fn new(format: OutputFormat, csv_header: &[&str]) -> anyhow::Result<Self> {
+        Reporter::with_writer(io::stdout(), format, csv_header)
+    }
+}
+
+impl<W: Write> Reporter<W> {
+    fn with_writer(out: W, format: OutputFormat, csv_header: &[&str]) -> anyhow::Result<Self> {
+        let mut reporter = Reporter {
+            format,
+            written: 0,
+            out,
+        };
+        if format == OutputFormat::Csv {
+            reporter.write_csv(csv_header)?;
+        }
+        Ok(reporter)
+    }
+
+    pub(crate) fn write(&mut self, record: &impl Record) -> anyhow::Result<()> {
+        match self.format {
+            OutputFormat::Text => {}
+            OutputFormat::Json => {
+                let separator = if self.written == 0 { "[\n" } else { ",\n" };
+                write!(self.out, "{}", separator)?;
+                serde_json::to_writer_pretty(&mut self.out, record)?;
+                self.out.flush()?;
+            }
+            OutputFormat::Ndjson => {
+                serde_json::to_writer(&mut self.out, record)?;
+                writeln!(self.out)?;
+            }
+            OutputFormat::Csv => self.write_csv(record.csv_row())?,
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    fn write_csv<I>(&mut self, record: I) -> anyhow::Result<()>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let mut writer = csv::Writer::from_writer(&mut self.out);
+        writer.write_record(record)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Close the JSON array, returning the writer
+    pub(crate) fn finish(mut self) -> anyhow::Result<W> {
+        if self.format == OutputFormat::Json {
+            writeln!(self.out, "{}", if self.written == 0 { "[]" } else { "\n]" })?;
+        }
+        Ok(self.out)
+    }
+}
+
+pub(crate) const FILE_CSV_HEADER: [&str; 52] = [
+    "path",
+    "target",
+    "valid",
+    "container",
+    "container_okay",
+    "duration",
+    "video_codec",
+    "video_codec_okay",
+    "pix_fmt",
+    "pix_fmt_okay",
+    "video_codec_tag",
+    "video_codec_tag_okay",
+    "video_profile",
+    "video_profile_okay",
+    "video_level",
+    "video_level_okay",
+    "hdr",
+    "hdr_okay",
+    "dolby_vision_profile",
+    "dolby_vision_profile_okay",
+    "resolution",
+    "resolution_okay",
+    "frame_rate",
+    "frame_rate_okay",
+    "video_bit_rate",
+    "video_bit_rate_okay",
+    "field_order",
+    "field_order_okay",
+    "audio_codec",
+    "audio_codec_okay",
+    "audio_selection",
+    "audio_channels",
+    "audio_channels_okay",
+    "channel_layout",
+    "channel_layout_okay",
+    "sample_rate",
+    "sample_rate_okay",
+    "compatible_audio",
+    "compatible_audio_okay",
+    "audio_language",
+    "audio_language_okay",
+    "default_audio",
+    "default_audio_okay",
+    "subtitle_codec",
+    "subtitle_codec_okay",
+    "subtitle_forced",
+    "subtitle_forced_okay",
+    "sidecars",
+    "unfixable",
+    "fix_status",
+    "fix_output",
+    "error",
+];
+
+fn csv_row(report: &FileReport) -> [String; FILE_CSV_HEADER.len()] {
+    let metadata = report.metadata.as_ref();
+    let validation = report.validation.as_ref();
+
+    let join = |values: Option<Vec<String>>| values.map(|v| v.join(";")).unwrap_or_default();
+    let okay =
+        |values: Option<Vec<bool>>| join(values.map(|v| v.iter().map(bool::to_string).collect()));
+    let video = validation.map(|v| v.video.as_slice()).unwrap_or_default();
+    let audio = validation.map(|v| v.audio.as_slice()).unwrap_or_default();
+    let subtitle = validation
+        .map(|v| v.subtitle.as_slice())
+        .unwrap_or_default();
+    let (codec_tag, codec_tag_okay) = optional_columns(video, |v| &v.codec_tag);
+    let (profile, profile_okay) = optional_columns(video, |v| &v.profile);
+    let (level, level_okay) = optional_columns(video, |v| &v.level);
+    let (hdr, hdr_okay) = optional_columns(video, |v| &v.hdr);
+    let (dolby_vision, dolby_vision_okay) = optional_columns(video, |v| &v.dolby_vision);
+    let (resolution, resolution_okay) = optional_columns(video, |v| &v.resolution);
+    let (frame_rate, frame_rate_okay) = optional_columns(video, |v| &v.frame_rate);
+    let (bit_rate, bit_rate_okay) = optional_columns(video, |v| &v.bit_rate);
+    let (field_order, field_order_okay) = optional_columns(video, |v| &v.interlaced);
+    let (channels, channels_okay) = optional_columns(audio, |a| &a.channels);
+    let (channel_layout, channel_layout_okay) = optional_columns(audio, |a| &a.channel_layout);
+    let (sample_rate, sample_rate_okay) = optional_columns(audio, |a| &a.sample_rate);
+    let (forced, forced_okay) = optional_columns(subtitle, |s| &s.forced);
+    let file_component = |get: fn(&FormatValidation) -> &Option<ComponentValidation>| {
+        let component = validation.and_then(|v| get(v).as_ref());
+        (
+            component.map(|c| c.value.clone()).unwrap_or_default(),
+            component.map(|c| c.okay.to_string()).unwrap_or_default(),
+        )
+    };
+    let (compatible_audio, compatible_audio_okay) = file_component(|v| &v.compatible_audio);
+    let (audio_language, audio_language_okay) = file_component(|v| &v.audio_language);
+    let (default_audio, default_audio_okay) = file_component(|v| &v.default_audio);
+
+    [
+        report.path.to_string_lossy().into_owned(),
+        report.target.clone(),
+        report.valid.map(|v| v.to_string()).unwrap_or_default(),
+        metadata.map(|m| m.container.clone()).unwrap_or_default(),
+        validation
+            .map(|v| v.container.okay.to_string())
+            .unwrap_or_default(),
+        metadata
+            .and_then(|m| m.duration)
+            .map(|d| d.to_string())
+            .unwrap_or_default(),
+        join(metadata.map(|m| m.video.iter().map(|v| v.codec.clone()).collect())),
+        okay(validation.map(|v| v.video.iter().map(|v| v.codec.okay).collect())),
+        join(metadata.map(|m| m.video.iter().map(|v| v.pix_fmt.clone()).collect())),
+        okay(validation.map(|v| v.video.iter().map(|v| v.pix_fmt.okay).collect())),
+        codec_tag,
+        codec_tag_okay,
+        profile,
+        profile_okay,
+        level,
+        level_okay,
+        hdr,
+        hdr_okay,
+        dolby_vision,
+        dolby_vision_okay,
+        resolution,
+        resolution_okay,
+        frame_rate,
+        frame_rate_okay,
+        bit_rate,
+        bit_rate_okay,
+        field_order,
+        field_order_okay,
+        join(metadata.map(|m| m.audio.iter().map(|a| a.codec.clone()).collect())),
+        okay(validation.map(|v| v.audio.iter().map(|a| a.codec.okay).collect())),
+        join(validation.map(|v| {
+            v.audio
+                .iter()
+                .map(|a| a.selection.as_str().to_string())
+                .collect()
+        })),
+        channels,
+        channels_okay,
+        channel_layout,
+        channel_layout_okay,
+        sample_rate,
+        sample_rate_okay,
+        compatible_audio,
+        compatible_audio_okay,
+        audio_language,
+        audio_language_okay,
+        default_audio,
+        default_audio_okay,
+        join(metadata.map(|m| m.subtitle.iter().map(|s| s.codec.clone()).collect())),
+        okay(validation.map(|v| v.subtitle.iter().map(|s| s.codec.okay).collect())),
+        forced,
+        forced_okay,
+        report
+            .sidecars
+            .iter()
+            .map(|s| s.path.to_string_lossy())
+            .join(";"),
+        report.unfixable.join(";"),
+        report
+            .fix
+            .as_ref()
+            .map(|f| f.status.as_str().to_string())
+            .unwrap_or_default(),
+        report
+            .fix
+            .as_ref()
+            .map(|f| f.output.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        report.error.clone().unwrap_or_default(),
+    ]
+}
+
+/// The values and results of a check that is only made when the target configures it, which
+/// are left empty otherwise
+fn optional_columns<T>(
+    streams: &[T],
+    get: fn(&T) -> &Option<ComponentValidation>,
+) -> (String, String) {
+    let components = streams.iter().filter_map(|s| get(s).as_ref()).collect_vec();
+    (
+        components.iter().map(|c| c.value.clone()).join(";"),
+        components.iter().map(|c| c.okay.to_string()).join(";"),
+    )
+}
+
+pub(crate) fn print_file(path: &Path, validation: &FormatValidation, sidecars: &[Sidecar]) {
+    println!();
+    println!(
+        "{}",
+        path.file_name().and_then(|n| n.to_str()).unwrap_or("..")
+    );
+    println!(
+        " - {}; {}; {}; {}{}; {}{}",
+        validation.audio.iter().map(report_audio).join(", "),
+        validation
+            .video
+            .iter()
+            .map(|v| report_component(&v.codec))
+            .join(", "),
+        report_component(&validation.container),
+        validation
+            .video
+            .iter()
+            .map(|v| report_component(&v.pix_fmt))
+            .join(", "),
+        validation
+            .video
+            .iter()
+            .flat_map(|v| v.optional())
+            .map(|c| format!("; {}", report_component(c)))
+            .join(""),
+        report_subtitles(validation),
+        [&validation.audio_language, &validation.default_audio]
+            .into_iter()
+            .flatten()
+            .map(|c| format!("; {}", report_component(c)))
+            .join(""),
+    );
+    for component in validation.components().filter(|c| !c.okay) {
+        println!("   - {}", component.reason);
+    }
+    for sidecar in sidecars {
+        println!("   sidecar {}", describe_sidecar(sidecar));
+    }
+}
+
+pub(crate) fn print_unfixable(reasons: &[String]) {
+    println!("   can't be fixed: {}", reasons.join("; "));
+}
+
+pub(crate) fn print_plan(fix: &FixReport) {
+    if fix.remux {
+        println!("   would remux into {}", fix.output.display());
+    } else {
+        println!("   would write {}", fix.output.display());
+    }
+    for stream in &fix.streams {
+        let source = match &stream.sidecar {
+            Some(path) => format!(
+                "sidecar {}",
+                path.file_name().and_then(|n| n.to_str()).unwrap_or("..")
+            ),
+            None => format!("stream {}", stream.index),
+        };
+        println!(
+            "   - {} ({} {}): {}",
+            source,
+            stream.kind.as_str(),
+            stream.codec,
+            match &stream.action {
+                StreamAction::Transcode(codec) if stream.added => {
+                    format!("add a new stream transcoded to {}", codec)
+                }
+                StreamAction::Copy => "copy".to_string(),
+                StreamAction::Transcode(codec) => format!("transcode to {}", codec),
+                StreamAction::Drop => "drop".to_string(),
+            }
+        );
+    }
+    println!("   {}", fix.command);
+}
+
+fn describe_sidecar(sidecar: &Sidecar) -> String {
+    let details = [
+        Some(sidecar.codec.as_str()),
+        sidecar.language.as_deref(),
+        Some("forced").filter(|_| sidecar.forced),
+        Some("sdh").filter(|_| sidecar.hearing_impaired),
+    ]
+    .into_iter()
+    .flatten()
+    .join(", ");
+    format!(
+        "{} ({})",
+        sidecar
+            .path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(".."),
+        details
+    )
+}
+
+fn report_audio(audio: &AudioValidation) -> String {
+    let components = audio.components().map(report_component).join(" ");
+    match audio.selection {
+        Selection::Checked => components,
+        // the target doesn't care whether these play
+        Selection::Kept | Selection::Dropped => {
+            format!("{} ({})", components, audio.selection.as_str())
+        }
+    }
+}
+
+fn report_subtitles(validation: &FormatValidation) -> String {
+    if validation.subtitle.is_empty() {
+        return "no subtitles".to_string();
+    }
+    validation
+        .subtitle
+        .iter()
+        .map(|s| s.components().map(report_component).join(" "))
+        .join(", ")
+}
+
+fn report_component(component: &ComponentValidation) -> String {
+    format!("{} {}", component.value, report_status(component.okay))
+}
+
+pub(crate) fn report_status(is_okay: bool) -> &'static str {
+    if is_okay {
+        "✅"
+    } else {
+        "❌"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_all(format: OutputFormat, reports: &[FileReport]) -> String {
+        let mut reporter = Reporter::with_writer(Vec::new(), format, &FILE_CSV_HEADER).unwrap();
+        for report in reports {
+            reporter.write(report).unwrap();
+        }
+        String::from_utf8(reporter.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn json_output_is_one_array() {
+        let reports = [
+            FileReport::new(Path::new("/nonexistent/a.mkv"), "test"),
+            FileReport::new(Path::new("/nonexistent/b.mkv"), "test"),
+        ];
+        let json = write_all(OutputFormat::Json, &reports);
+
+        assert!(json.starts_with("[\n{"));
+        assert!(json.contains("},\n{"));
+        assert!(json.ends_with("}\n]\n"));
+        let parsed: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1]["path"], "/nonexistent/b.mkv");
+
+        assert_eq!(write_all(OutputFormat::Json, &[]), "[]\n");
+    }
+
+    #[test]
+    fn csv_rows_line_up_with_header() {
+        let mut report = FileReport::new(Path::new("/nonexistent/a.mkv"), "test");
+        report.error = Some("could not probe".to_string());
+        let csv = write_all(OutputFormat::Csv, &[report]);
+
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let header = reader.headers().unwrap().clone();
+        assert_eq!(header.len(), FILE_CSV_HEADER.len());
+        let row = reader.records().next().unwrap().unwrap();
+        assert_eq!(row.len(), header.len());
+        let column = |name: &str| &row[header.iter().position(|h| h == name).unwrap()];
+        assert_eq!(column("path"), "/nonexistent/a.mkv");
+        assert_eq!(column("target"), "test");
+        assert_eq!(column("error"), "could not probe");
+    }
+}
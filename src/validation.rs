@@ -1,19 +1,230 @@
-use crate::metadata;
+use itertools::Itertools;
+use serde::Serialize;
+use std::iter;
 
+use crate::metadata::{self, HdrFormat};
+
+use super::AudioSelection;
+use super::AudioStrategy;
+use super::DefaultAudio;
 use super::FormatSpec;
 use super::Formats;
+use super::LanguageSpec;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub(crate) struct FormatValidation {
-    pub(crate) audio_okay: bool,
-    pub(crate) video_okay: bool,
-    pub(crate) container_okay: bool,
-    pub(crate) pix_fmt_okay: bool,
+    pub(crate) audio: Vec<AudioValidation>,
+    pub(crate) video: Vec<VideoValidation>,
+    pub(crate) subtitle: Vec<SubtitleValidation>,
+    pub(crate) container: ComponentValidation,
+    /// Whether any audio stream is compatible, checked instead of each audio stream when the
+    /// target keeps incompatible audio alongside a compatible copy
+    pub(crate) compatible_audio: Option<ComponentValidation>,
+    /// Whether an audio stream is in one of the target's languages, `None` when the target
+    /// doesn't check languages
+    pub(crate) audio_language: Option<ComponentValidation>,
+    /// Whether the right audio stream is flagged default, `None` when the target doesn't check
+    /// it or the file has no audio in any of its languages
+    pub(crate) default_audio: Option<ComponentValidation>,
+}
+
+/// Validation of a single video stream, in the same order as [`metadata::FileMetadata::video`]
+#[derive(Debug, Serialize)]
+pub(crate) struct VideoValidation {
+    pub(crate) codec: ComponentValidation,
+    pub(crate) pix_fmt: ComponentValidation,
+    /// `None` when the target doesn't check it or the container doesn't use codec tags
+    pub(crate) codec_tag: Option<ComponentValidation>,
+    /// `None` when the target has no profile rules for the codec
+    pub(crate) profile: Option<ComponentValidation>,
+    pub(crate) level: Option<ComponentValidation>,
+    /// `None` when the target doesn't check it
+    pub(crate) hdr: Option<ComponentValidation>,
+    /// `None` when the target doesn't check it or the stream isn't Dolby Vision
+    pub(crate) dolby_vision: Option<ComponentValidation>,
+    /// `None` when the target doesn't limit it
+    pub(crate) resolution: Option<ComponentValidation>,
+    pub(crate) frame_rate: Option<ComponentValidation>,
+    pub(crate) bit_rate: Option<ComponentValidation>,
+    /// `None` unless the target rejects interlaced video
+    pub(crate) interlaced: Option<ComponentValidation>,
+}
+
+impl VideoValidation {
+    pub(crate) fn components(&self) -> impl Iterator<Item = &ComponentValidation> {
+        [&self.codec, &self.pix_fmt]
+            .into_iter()
+            .chain(self.optional())
+    }
+
+    /// The checks that are only made when the target configures them
+    pub(crate) fn optional(&self) -> impl Iterator<Item = &ComponentValidation> {
+        [
+            &self.codec_tag,
+            &self.profile,
+            &self.level,
+            &self.hdr,
+            &self.dolby_vision,
+            &self.resolution,
+            &self.frame_rate,
+            &self.bit_rate,
+            &self.interlaced,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    pub(crate) fn dynamic_range_okay(&self) -> bool {
+        [&self.hdr, &self.dolby_vision]
+            .into_iter()
+            .flatten()
+            .all(|c| c.okay)
+    }
+
+    /// Every check apart from the codec tag and dynamic range ones, which fixes handle
+    /// separately
+    pub(crate) fn picture_components(&self) -> impl Iterator<Item = &ComponentValidation> {
+        [&self.codec, &self.pix_fmt].into_iter().chain(
+            [
+                &self.profile,
+                &self.level,
+                &self.resolution,
+                &self.frame_rate,
+                &self.bit_rate,
+                &self.interlaced,
+            ]
+            .into_iter()
+            .flatten(),
+        )
+    }
+}
+
+/// Validation of a single audio stream, in the same order as [`metadata::FileMetadata::audio`]
+#[derive(Debug, Serialize)]
+pub(crate) struct AudioValidation {
+    /// Whether the stream's checks count, following the target's audio selection
+    pub(crate) selection: Selection,
+    pub(crate) codec: ComponentValidation,
+    /// `None` when the target doesn't check it
+    pub(crate) channels: Option<ComponentValidation>,
+    pub(crate) channel_layout: Option<ComponentValidation>,
+    pub(crate) sample_rate: Option<ComponentValidation>,
+}
+
+impl AudioValidation {
+    pub(crate) fn components(&self) -> impl Iterator<Item = &ComponentValidation> {
+        iter::once(&self.codec).chain(self.optional())
+    }
+
+    /// The checks that are only made when the target configures them
+    pub(crate) fn optional(&self) -> impl Iterator<Item = &ComponentValidation> {
+        [&self.channels, &self.channel_layout, &self.sample_rate]
+            .into_iter()
+            .flatten()
+    }
+}
+
+/// What a fix does with an audio stream under the target's audio selection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Selection {
+    /// Checked against the target and transcoded if it fails
+    Checked,
+    /// Copied as is, so its checks don't decide whether the file is valid
+    Kept,
+    /// Left out of a fix, so its checks don't decide whether the file is valid
+    Dropped,
+}
+
+impl Selection {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Selection::Checked => "checked",
+            Selection::Kept => "kept",
+            Selection::Dropped => "dropped",
+        }
+    }
+}
+
+/// Validation of a single subtitle stream, in the same order as [`metadata::FileMetadata::subtitle`]
+#[derive(Debug, Serialize)]
+pub(crate) struct SubtitleValidation {
+    pub(crate) codec: ComponentValidation,
+    /// `None` unless the target checks forced flags and the stream is titled as forced
+    pub(crate) forced: Option<ComponentValidation>,
+}
+
+impl SubtitleValidation {
+    pub(crate) fn components(&self) -> impl Iterator<Item = &ComponentValidation> {
+        iter::once(&self.codec).chain(&self.forced)
+    }
+}
+
+/// The outcome of checking one observed value against its [`Formats`] rule or limit
+#[derive(Debug, Serialize)]
+pub(crate) struct ComponentValidation {
+    pub(crate) value: String,
+    pub(crate) okay: bool,
+    pub(crate) rule: RuleMatch,
+    pub(crate) reason: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RuleMatch {
+    /// The value is in an allow list
+    AllowHit,
+    /// The value is missing from an allow list
+    AllowMiss,
+    /// The value is in a reject list
+    RejectHit,
+    /// The value is missing from a reject list
+    RejectMiss,
+    /// The value is no more than a configured maximum
+    WithinLimit,
+    /// The value is over a configured maximum
+    ExceedsLimit,
+    /// The value couldn't be determined, so it is assumed to be fine
+    Unknown,
+    /// A compatible stream is present alongside incompatible ones
+    FallbackPresent,
+    /// No stream is compatible
+    FallbackMissing,
+    /// The stream the rule picks out has the flag it requires
+    FlagSet,
+    /// The stream the rule picks out is missing the flag it requires
+    FlagMissing,
 }
 
 impl FormatValidation {
     pub(crate) fn is_valid(&self) -> bool {
-        self.audio_okay && self.video_okay && self.container_okay && self.pix_fmt_okay
+        self.components().all(|c| c.okay)
+    }
+
+    /// Failed checks that no fix can correct, e.g. a language the file has no audio in
+    pub(crate) fn unfixable(&self) -> impl Iterator<Item = &ComponentValidation> {
+        self.audio_language.iter().filter(|c| !c.okay)
+    }
+
+    /// Failed checks that a fix can correct
+    pub(crate) fn fixable(&self) -> impl Iterator<Item = &ComponentValidation> {
+        let unfixable = self.unfixable().collect_vec();
+        self.components()
+            .filter(move |c| !c.okay && !unfixable.iter().any(|u| std::ptr::eq(*u, *c)))
+    }
+
+    /// Every individual check that decides whether the file is valid
+    pub(crate) fn components(&self) -> impl Iterator<Item = &ComponentValidation> {
+        self.audio
+            .iter()
+            .filter(|a| self.compatible_audio.is_none() && a.selection == Selection::Checked)
+            .flat_map(|a| a.components())
+            .chain(&self.compatible_audio)
+            .chain(self.video.iter().flat_map(|v| v.components()))
+            .chain(self.subtitle.iter().flat_map(|s| s.components()))
+            .chain(iter::once(&self.container))
+            .chain(&self.audio_language)
+            .chain(&self.default_audio)
     }
 }
 
@@ -21,40 +232,505 @@ pub(crate) fn validate_format(
     file: &metadata::FileMetadata,
     format: &FormatSpec,
 ) -> FormatValidation {
-    let audio_okay = validate_format_component(&format.audio, &file.audio.codec);
-    let video_okay = validate_format_component(&format.video, &file.video.codec);
-    let container_okay = validate_format_component(&format.container, &file.container);
-    let pix_fmt_okay = validate_format_component(&format.pix_fmt, &file.video.pix_fmt);
+    let audio: Vec<_> = file
+        .audio
+        .iter()
+        .zip(audio_selection(file, format))
+        .map(|(a, selection)| AudioValidation {
+            selection,
+            codec: validate_format_component("audio codec", &format.audio, &a.codec),
+            channels: format.max_audio_channels.map(|max| {
+                validate_limit(
+                    "audio",
+                    Some((a.channels as f64, format_channels(a.channels))),
+                    (max as f64, format_channels(max)),
+                )
+            }),
+            channel_layout: format.audio_channel_layout.as_ref().map(|rule| {
+                validate_optional_component("channel layout", rule, a.channel_layout.as_deref())
+            }),
+            sample_rate: format.audio_sample_rate.as_ref().map(|rule| {
+                validate_optional_component("sample rate", rule, a.sample_rate.as_deref())
+            }),
+        })
+        .collect();
+    let video =
+        file.video
+            .iter()
+            .map(|v| {
+                let profile_spec = format.video_profiles.get(&v.codec);
+                VideoValidation {
+                    codec: validate_format_component("video codec", &format.video, &v.codec),
+                    pix_fmt: validate_format_component("pix_fmt", &format.pix_fmt, &v.pix_fmt),
+                    codec_tag: format
+                        .video_codec_tag
+                        .as_ref()
+                        .zip(v.codec_tag.as_ref())
+                        .map(|(rule, tag)| validate_format_component("codec tag", rule, tag)),
+                    profile: profile_spec.map(|spec| {
+                        validate_optional_component(
+                            &format!("{} profile", v.codec),
+                            &spec.profile,
+                            v.profile.as_deref(),
+                        )
+                    }),
+                    level: profile_spec.and_then(|spec| spec.max_level).map(|max| {
+                        validate_limit(
+                            &format!("{} level", v.codec),
+                            v.level.map(|level| (level, level.to_string())),
+                            (max, max.to_string()),
+                        )
+                    }),
+                    hdr: format.hdr.as_ref().map(|rule| {
+                        validate_format_component("dynamic range", rule, v.hdr.as_str())
+                    }),
+                    dolby_vision: format
+                        .dolby_vision_profile
+                        .as_ref()
+                        .zip(v.dolby_vision.as_ref())
+                        .map(|(rule, dv)| {
+                            validate_format_component(
+                                "Dolby Vision profile",
+                                rule,
+                                &dv.profile.to_string(),
+                            )
+                        }),
+                    interlaced: match format.interlaced {
+                        Some(false) => Some(validate_interlaced(v.field_order.as_deref())),
+                        _ => None,
+                    },
+                    resolution: validate_resolution(v, format),
+                    frame_rate: format.max_frame_rate.map(|max| {
+                        validate_limit(
+                            "frame rate",
+                            v.frame_rate.map(|rate| (rate, format_frame_rate(rate))),
+                            (max, format_frame_rate(max)),
+                        )
+                    }),
+                    bit_rate: format.max_video_bitrate.map(|max| {
+                        validate_limit(
+                            "video bitrate",
+                            v.bit_rate.map(|b| (b as f64, format_bit_rate(b))),
+                            (max as f64, format_bit_rate(max)),
+                        )
+                    }),
+                }
+            })
+            .collect();
+    let languages = format.languages.as_ref();
+    let subtitle = file
+        .subtitle
+        .iter()
+        .map(|s| SubtitleValidation {
+            codec: validate_format_component("subtitle codec", &format.subtitle, &s.codec),
+            forced: languages
+                .filter(|spec| spec.flag_forced_subtitles && titled_forced(s))
+                .map(|_| validate_forced_flag(s)),
+        })
+        .collect();
+    let container = validate_format_component("container", &format.container, &file.container);
+    let compatible_audio = match format.audio_strategy {
+        AudioStrategy::Replace => None,
+        AudioStrategy::AddCompat => Some(validate_compatible_audio(file, &audio)),
+    };
 
     FormatValidation {
-        audio_okay,
-        video_okay,
-        container_okay,
-        pix_fmt_okay,
+        audio,
+        video,
+        subtitle,
+        container,
+        compatible_audio,
+        audio_language: languages.map(|spec| validate_audio_language(file, spec)),
+        default_audio: languages.and_then(|spec| validate_default_audio(file, spec)),
+    }
+}
+
+/// The most preferred of the target's languages that the file has audio in
+pub(crate) fn preferred_language<'a>(
+    file: &metadata::FileMetadata,
+    spec: &'a LanguageSpec,
+) -> Option<&'a str> {
+    spec.audio
+        .iter()
+        .find(|&language| {
+            file.audio
+                .iter()
+                .any(|a| a.language.as_ref() == Some(language))
+        })
+        .map(|language| language.as_str())
+}
+
+/// Pick out the audio streams a fix keeps under the target's audio selection, in the same
+/// order as the file's audio streams; everything is checked when the file has no audio in
+/// any of the target's languages
+fn audio_selection(file: &metadata::FileMetadata, format: &FormatSpec) -> Vec<Selection> {
+    let languages = format.languages.as_ref();
+    let policy = languages.and_then(|spec| spec.audio_selection);
+    let preferred = languages.and_then(|spec| preferred_language(file, spec));
+    let (Some(policy), Some(preferred)) = (policy, preferred) else {
+        return vec![Selection::Checked; file.audio.len()];
+    };
+    let original = file
+        .audio
+        .iter()
+        .find(|a| a.disposition.original)
+        .or(file.audio.first())
+        .map(|a| a.index);
+
+    file.audio
+        .iter()
+        .map(|a| match policy {
+            _ if a.language.as_deref() == Some(preferred) => Selection::Checked,
+            AudioSelection::KeepAll => Selection::Kept,
+            AudioSelection::PreferredAndOriginal if Some(a.index) == original => Selection::Kept,
+            AudioSelection::PreferredOnly | AudioSelection::PreferredAndOriginal => {
+                Selection::Dropped
+            }
+        })
+        .collect()
+}
+
+fn validate_audio_language(
+    file: &metadata::FileMetadata,
+    spec: &LanguageSpec,
+) -> ComponentValidation {
+    if let Some(language) = preferred_language(file, spec) {
+        return ComponentValidation {
+            value: language.to_string(),
+            okay: true,
+            rule: RuleMatch::AllowHit,
+            reason: format!("audio language {} is present", language),
+        };
+    }
+    // an untagged stream could well be in the right language
+    if file.audio.iter().any(|a| a.language.is_none()) {
+        return unknown("audio language");
+    }
+
+    ComponentValidation {
+        value: file
+            .audio
+            .iter()
+            .filter_map(|a| a.language.as_deref())
+            .join("/"),
+        okay: false,
+        rule: RuleMatch::AllowMiss,
+        reason: format!(
+            "no audio stream is in a required language ({})",
+            spec.audio.iter().join(", ")
+        ),
+    }
+}
+
+fn validate_default_audio(
+    file: &metadata::FileMetadata,
+    spec: &LanguageSpec,
+) -> Option<ComponentValidation> {
+    if spec.default_audio == DefaultAudio::Any {
+        return None;
+    }
+    let preferred = preferred_language(file, spec)?;
+
+    let (value, okay, reason) = match file.audio.iter().find(|a| a.disposition.default) {
+        None => (
+            "no default".to_string(),
+            false,
+            "no audio stream is flagged default".to_string(),
+        ),
+        Some(stream) => {
+            let language = stream.language.as_deref().unwrap_or("und");
+            let value = format!("default {}", language);
+            if language != preferred {
+                let reason = format!(
+                    "default audio stream {} ({}) is not in the preferred language {}",
+                    stream.index, language, preferred
+                );
+                (value, false, reason)
+            } else if spec.default_audio == DefaultAudio::PreferredFirst
+                && file.audio.first().is_some_and(|a| a.index != stream.index)
+            {
+                let reason = format!(
+                    "default audio stream {} ({}) is not the first audio stream",
+                    stream.index, language
+                );
+                (value, false, reason)
+            } else {
+                let reason = format!(
+                    "default audio stream {} is in the preferred language {}",
+                    stream.index, language
+                );
+                (value, true, reason)
+            }
+        }
+    };
+
+    Some(ComponentValidation {
+        value,
+        okay,
+        rule: if okay {
+            RuleMatch::FlagSet
+        } else {
+            RuleMatch::FlagMissing
+        },
+        reason,
+    })
+}
+
+/// Whether a subtitle stream is named as forced, e.g. "English (Forced)"; forced subtitles
+/// only cover foreign dialogue and signs, so players have to show them automatically
+fn titled_forced(subtitle: &metadata::SubtitleMetadata) -> bool {
+    subtitle
+        .title
+        .as_ref()
+        .is_some_and(|t| t.to_lowercase().contains("forced"))
+}
+
+fn validate_forced_flag(subtitle: &metadata::SubtitleMetadata) -> ComponentValidation {
+    if subtitle.disposition.forced {
+        ComponentValidation {
+            value: "forced".to_string(),
+            okay: true,
+            rule: RuleMatch::FlagSet,
+            reason: format!(
+                "forced subtitle stream {} is flagged forced",
+                subtitle.index
+            ),
+        }
+    } else {
+        ComponentValidation {
+            value: "not flagged forced".to_string(),
+            okay: false,
+            rule: RuleMatch::FlagMissing,
+            reason: format!(
+                "subtitle stream {} is titled as forced but not flagged forced",
+                subtitle.index
+            ),
+        }
     }
 }
 
-fn validate_format_component(format: &Formats, value: &String) -> bool {
+fn validate_compatible_audio(
+    file: &metadata::FileMetadata,
+    audio: &[AudioValidation],
+) -> ComponentValidation {
+    match file
+        .audio
+        .iter()
+        .zip(audio)
+        .find(|(_, a)| a.selection == Selection::Checked && a.components().all(|c| c.okay))
+    {
+        Some((stream, _)) => ComponentValidation {
+            value: stream.codec.clone(),
+            okay: true,
+            rule: RuleMatch::FallbackPresent,
+            reason: format!(
+                "audio stream {} ({}) is compatible",
+                stream.index, stream.codec
+            ),
+        },
+        None => ComponentValidation {
+            value: "none".to_string(),
+            okay: false,
+            rule: RuleMatch::FallbackMissing,
+            reason: "no audio stream is compatible".to_string(),
+        },
+    }
+}
+
+fn validate_format_component(name: &str, format: &Formats, value: &str) -> ComponentValidation {
+    let (rule, okay, reason) = match format {
+        Formats::Allow(items) if allow(items, value) => (
+            RuleMatch::AllowHit,
+            true,
+            format!("{} {} is allowed", name, value),
+        ),
+        Formats::Allow(items) => (
+            RuleMatch::AllowMiss,
+            false,
+            format!(
+                "{} {} is not in the allowed list ({})",
+                name,
+                value,
+                items.iter().join(", ")
+            ),
+        ),
+        Formats::Reject(items) if reject(items, value) => (
+            RuleMatch::RejectMiss,
+            true,
+            format!("{} {} is not rejected", name, value),
+        ),
+        Formats::Reject(_) => (
+            RuleMatch::RejectHit,
+            false,
+            format!("{} {} is rejected", name, value),
+        ),
+    };
+
+    ComponentValidation {
+        value: value.to_string(),
+        okay,
+        rule,
+        reason,
+    }
+}
+
+/// Whether the target accepts a dynamic range format, e.g. the base layer left after removing
+/// Dolby Vision
+pub(crate) fn hdr_allowed(format: &FormatSpec, hdr: HdrFormat) -> bool {
+    format
+        .hdr
+        .as_ref()
+        .is_none_or(|rule| is_allowed(rule, hdr.as_str()))
+}
+
+/// Whether a rule accepts a value, for fixes choosing between possible outputs
+pub(crate) fn is_allowed(format: &Formats, value: &str) -> bool {
     match format {
         Formats::Allow(items) => allow(items, value),
         Formats::Reject(items) => reject(items, value),
     }
 }
 
-fn allow(format: &[String], value: &String) -> bool {
-    format.contains(value)
+fn validate_optional_component(
+    name: &str,
+    format: &Formats,
+    value: Option<&str>,
+) -> ComponentValidation {
+    match value {
+        Some(value) => validate_format_component(name, format, value),
+        None => unknown(name),
+    }
+}
+
+fn validate_resolution(
+    video: &metadata::VideoMetadata,
+    format: &FormatSpec,
+) -> Option<ComponentValidation> {
+    if format.max_width.is_none() && format.max_height.is_none() {
+        return None;
+    }
+    let limit = [
+        format.max_width.map(|w| format!("width {}", w)),
+        format.max_height.map(|h| format!("height {}", h)),
+    ]
+    .into_iter()
+    .flatten()
+    .join(", ");
+
+    let (value, rule, okay, reason) = match (video.width, video.height) {
+        (Some(width), Some(height)) => {
+            let value = format!("{}x{}", width, height);
+            let exceeds = format.max_width.is_some_and(|max| width > max)
+                || format.max_height.is_some_and(|max| height > max);
+            if exceeds {
+                let reason = format!("resolution {} exceeds the limit ({})", value, limit);
+                (value, RuleMatch::ExceedsLimit, false, reason)
+            } else {
+                let reason = format!("resolution {} is within the limit ({})", value, limit);
+                (value, RuleMatch::WithinLimit, true, reason)
+            }
+        }
+        _ => return Some(unknown("resolution")),
+    };
+
+    Some(ComponentValidation {
+        value,
+        okay,
+        rule,
+        reason,
+    })
+}
+
+fn validate_interlaced(field_order: Option<&str>) -> ComponentValidation {
+    let (rule, okay, reason) = match field_order {
+        None => return unknown("field order"),
+        Some("progressive") => (
+            RuleMatch::RejectMiss,
+            true,
+            "video is progressive".to_string(),
+        ),
+        Some(order) => (
+            RuleMatch::RejectHit,
+            false,
+            format!("video is interlaced (field order {})", order),
+        ),
+    };
+
+    ComponentValidation {
+        value: field_order.unwrap_or_default().to_string(),
+        okay,
+        rule,
+        reason,
+    }
+}
+
+/// Check a value against a maximum, each given as the number compared and how to display it
+fn validate_limit(
+    name: &str,
+    value: Option<(f64, String)>,
+    (max, max_display): (f64, String),
+) -> ComponentValidation {
+    let (value, rule, okay, reason) = match value {
+        Some((value, display)) if value > max => {
+            let reason = format!("{} {} exceeds the limit of {}", name, display, max_display);
+            (display, RuleMatch::ExceedsLimit, false, reason)
+        }
+        Some((_, display)) => {
+            let reason = format!(
+                "{} {} is within the limit of {}",
+                name, display, max_display
+            );
+            (display, RuleMatch::WithinLimit, true, reason)
+        }
+        None => return unknown(name),
+    };
+
+    ComponentValidation {
+        value,
+        okay,
+        rule,
+        reason,
+    }
+}
+
+/// A value ffprobe couldn't report, which is given the benefit of the doubt
+fn unknown(name: &str) -> ComponentValidation {
+    ComponentValidation {
+        value: format!("unknown {}", name),
+        okay: true,
+        rule: RuleMatch::Unknown,
+        reason: format!("{} is unknown, assuming it is acceptable", name),
+    }
+}
+
+fn format_frame_rate(rate: f64) -> String {
+    let rate = format!("{:.3}", rate);
+    format!("{} fps", rate.trim_end_matches('0').trim_end_matches('.'))
 }
 
-fn reject(format: &[String], value: &String) -> bool {
+fn format_channels(channels: i64) -> String {
+    format!("{} channels", channels)
+}
+
+fn format_bit_rate(bit_rate: u64) -> String {
+    format!("{} kb/s", bit_rate / 1000)
+}
+
+fn allow(format: &[String], value: &str) -> bool {
+    format.iter().any(|f| f == value)
+}
+
+fn reject(format: &[String], value: &str) -> bool {
     !allow(format, value)
 }
 
 #[cfg(test)]
 mod test {
-    use itertools::Itertools;
-
     use super::*;
-    use crate::metadata::{AudioMetadata, FileMetadata, VideoMetadata};
+    use crate::metadata::{
+        AudioMetadata, Disposition, DolbyVision, FileMetadata, SubtitleMetadata, VideoMetadata,
+    };
+    use crate::VideoProfileSpec;
 
     fn str_vec(v: Vec<&str>) -> Vec<String> {
         v.iter().map(|x| x.to_string()).collect_vec()
@@ -64,16 +740,40 @@ mod test {
         FileMetadata {
             container: container.to_string(),
             duration: None,
-            video: VideoMetadata {
+            video: vec![VideoMetadata {
                 index: 0,
                 codec: vcodec.to_string(),
+                codec_tag: None,
                 pix_fmt: "".to_string(),
-            },
-            audio: AudioMetadata {
-                index: 1,
-                codec: acodec.to_string(),
-                channels: 2,
-            },
+                duration: None,
+                width: Some(1920),
+                height: Some(1080),
+                frame_rate: Some(24000.0 / 1001.0),
+                field_order: Some("progressive".to_string()),
+                bit_rate: Some(8_000_000),
+                profile: Some("High".to_string()),
+                level: Some(4.1),
+                color_transfer: Some("bt709".to_string()),
+                color_primaries: Some("bt709".to_string()),
+                color_space: Some("bt709".to_string()),
+                hdr: HdrFormat::Sdr,
+                dolby_vision: None,
+            }],
+            audio: vec![mk_audio(1, acodec, "eng")],
+            subtitle: vec![],
+            other: vec![],
+        }
+    }
+
+    fn mk_audio(index: i64, codec: &str, language: &str) -> AudioMetadata {
+        AudioMetadata {
+            index,
+            codec: codec.to_string(),
+            channels: 2,
+            channel_layout: Some("stereo".to_string()),
+            sample_rate: Some("48000".to_string()),
+            language: Some(language.to_string()),
+            disposition: Disposition::default(),
         }
     }
 
@@ -82,7 +782,7 @@ mod test {
             audio: Formats::Allow(str_vec(audio)),
             video: Formats::Allow(str_vec(video)),
             container: Formats::Allow(str_vec(container)),
-            pix_fmt: Formats::Reject(vec![]),
+            ..FormatSpec::default()
         }
     }
 
@@ -91,7 +791,7 @@ mod test {
             audio: Formats::Reject(str_vec(audio)),
             video: Formats::Reject(str_vec(video)),
             container: Formats::Reject(str_vec(container)),
-            pix_fmt: Formats::Reject(vec![]),
+            ..FormatSpec::default()
         }
     }
 
@@ -166,4 +866,465 @@ mod test {
         let validation = validate_format(&metadata, &format);
         assert!(!validation.is_valid());
     }
+
+    #[test]
+    fn format_validation_multiple_audio_valid() {
+        let format = mk_spec_allow(vec!["aac", "ac3"], vec!["h264"], vec!["matroska"]);
+        let mut metadata = mk_metadata("matroska", "h264", "aac");
+        metadata.audio.push(AudioMetadata {
+            channels: 6,
+            channel_layout: Some("5.1(side)".to_string()),
+            ..mk_audio(2, "ac3", "eng")
+        });
+
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.is_valid());
+    }
+
+    #[test]
+    fn format_validation_multiple_audio_one_invalid() {
+        let format = mk_spec_allow(vec!["aac", "ac3"], vec!["h264"], vec!["matroska"]);
+        let mut metadata = mk_metadata("matroska", "h264", "aac");
+        metadata.audio.push(mk_audio(2, "opus", "eng"));
+
+        let validation = validate_format(&metadata, &format);
+        assert!(!validation.is_valid());
+        assert!(validation.audio[0].codec.okay);
+        assert!(!validation.audio[1].codec.okay);
+    }
+
+    #[test]
+    fn format_validation_no_subtitles_valid() {
+        let mut format = mk_spec_allow(vec!["aac"], vec!["h264"], vec!["matroska"]);
+        format.subtitle = Formats::Allow(str_vec(vec!["subrip"]));
+        let metadata = mk_metadata("matroska", "h264", "aac");
+
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.is_valid());
+    }
+
+    #[test]
+    fn format_validation_reject_invalid_subtitle() {
+        let mut format = mk_spec_reject(vec![], vec![], vec![]);
+        format.subtitle = Formats::Reject(str_vec(vec!["hdmv_pgs_subtitle", "dvd_subtitle"]));
+        let mut metadata = mk_metadata("matroska", "h264", "aac");
+        metadata.subtitle.push(SubtitleMetadata {
+            index: 2,
+            codec: "subrip".to_string(),
+            language: Some("eng".to_string()),
+            title: None,
+            disposition: Disposition::default(),
+        });
+        metadata.subtitle.push(SubtitleMetadata {
+            index: 3,
+            codec: "hdmv_pgs_subtitle".to_string(),
+            language: Some("eng".to_string()),
+            title: None,
+            disposition: Disposition::default(),
+        });
+
+        let validation = validate_format(&metadata, &format);
+        assert!(!validation.is_valid());
+        assert!(validation.subtitle[0].codec.okay);
+        assert!(!validation.subtitle[1].codec.okay);
+    }
+
+    #[test]
+    fn format_validation_explains_allow_miss() {
+        let format = mk_spec_allow(vec!["aac"], vec!["h264", "hevc"], vec!["matroska"]);
+        let metadata = mk_metadata("matroska", "vp9", "aac");
+
+        let validation = validate_format(&metadata, &format);
+        let video = &validation.video[0].codec;
+        assert_eq!(video.value, "vp9");
+        assert_eq!(video.rule, RuleMatch::AllowMiss);
+        assert_eq!(
+            video.reason,
+            "video codec vp9 is not in the allowed list (h264, hevc)"
+        );
+        assert_eq!(validation.audio[0].codec.rule, RuleMatch::AllowHit);
+    }
+
+    #[test]
+    fn format_validation_explains_reject_hit() {
+        let mut format = mk_spec_reject(vec![], vec![], vec![]);
+        format.pix_fmt = Formats::Reject(str_vec(vec!["yuv420p10le"]));
+        let mut metadata = mk_metadata("matroska", "hevc", "aac");
+        metadata.video[0].pix_fmt = "yuv420p10le".to_string();
+
+        let validation = validate_format(&metadata, &format);
+        let pix_fmt = &validation.video[0].pix_fmt;
+        assert!(!pix_fmt.okay);
+        assert_eq!(pix_fmt.rule, RuleMatch::RejectHit);
+        assert_eq!(pix_fmt.reason, "pix_fmt yuv420p10le is rejected");
+        assert_eq!(validation.container.rule, RuleMatch::RejectMiss);
+    }
+
+    #[test]
+    fn format_validation_no_limits_configured() {
+        let format = mk_spec_reject(vec![], vec![], vec![]);
+        let metadata = mk_metadata("matroska", "h264", "aac");
+
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.video[0].optional().next().is_none());
+    }
+
+    #[test]
+    fn format_validation_within_limits() {
+        let mut format = mk_spec_reject(vec![], vec![], vec![]);
+        format.max_width = Some(1920);
+        format.max_height = Some(1080);
+        format.max_frame_rate = Some(30.0);
+        format.max_video_bitrate = Some(10_000_000);
+        let metadata = mk_metadata("matroska", "h264", "aac");
+
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.is_valid());
+        let video = &validation.video[0];
+        assert_eq!(video.optional().count(), 3);
+        assert_eq!(video.frame_rate.as_ref().unwrap().value, "23.976 fps");
+        assert_eq!(
+            video.resolution.as_ref().unwrap().reason,
+            "resolution 1920x1080 is within the limit (width 1920, height 1080)"
+        );
+    }
+
+    #[test]
+    fn format_validation_exceeds_limits() {
+        let mut format = mk_spec_reject(vec![], vec![], vec![]);
+        format.max_height = Some(720);
+        format.max_frame_rate = Some(60.0);
+        format.max_video_bitrate = Some(5_000_000);
+        let mut metadata = mk_metadata("matroska", "h264", "aac");
+        metadata.video[0].frame_rate = None;
+
+        let validation = validate_format(&metadata, &format);
+        assert!(!validation.is_valid());
+        let video = &validation.video[0];
+        let resolution = video.resolution.as_ref().unwrap();
+        assert_eq!(resolution.rule, RuleMatch::ExceedsLimit);
+        assert_eq!(
+            resolution.reason,
+            "resolution 1920x1080 exceeds the limit (height 720)"
+        );
+        let frame_rate = video.frame_rate.as_ref().unwrap();
+        assert!(frame_rate.okay);
+        assert_eq!(frame_rate.rule, RuleMatch::Unknown);
+        assert_eq!(
+            video.bit_rate.as_ref().unwrap().reason,
+            "video bitrate 8000 kb/s exceeds the limit of 5000 kb/s"
+        );
+    }
+
+    #[test]
+    fn format_validation_profile_and_level() {
+        let mut format = mk_spec_reject(vec![], vec![], vec![]);
+        format.video_profiles.insert(
+            "h264".to_string(),
+            VideoProfileSpec {
+                profile: Formats::Allow(str_vec(vec!["Main", "High"])),
+                max_level: Some(4.1),
+            },
+        );
+        format.video_profiles.insert(
+            "hevc".to_string(),
+            VideoProfileSpec {
+                profile: Formats::Allow(str_vec(vec!["Main"])),
+                max_level: None,
+            },
+        );
+        let mut metadata = mk_metadata("matroska", "h264", "aac");
+
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.is_valid());
+
+        metadata.video[0].profile = Some("High 4:4:4 Predictive".to_string());
+        metadata.video[0].level = Some(5.2);
+        let validation = validate_format(&metadata, &format);
+        let video = &validation.video[0];
+        assert_eq!(video.profile.as_ref().unwrap().rule, RuleMatch::AllowMiss);
+        assert_eq!(
+            video.level.as_ref().unwrap().reason,
+            "h264 level 5.2 exceeds the limit of 4.1"
+        );
+
+        // rules only apply to their own codec
+        let mut metadata = mk_metadata("matroska", "vp9", "aac");
+        metadata.video[0].profile = Some("Profile 2".to_string());
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.video[0].profile.is_none());
+        assert!(validation.is_valid());
+    }
+
+    #[test]
+    fn format_validation_audio_channels_layout_and_sample_rate() {
+        let mut format = mk_spec_reject(vec![], vec![], vec![]);
+        format.max_audio_channels = Some(2);
+        format.audio_channel_layout = Some(Formats::Allow(str_vec(vec!["mono", "stereo"])));
+        format.audio_sample_rate = Some(Formats::Allow(str_vec(vec!["44100", "48000"])));
+        let mut metadata = mk_metadata("matroska", "h264", "aac");
+        metadata.audio.push(AudioMetadata {
+            channels: 6,
+            channel_layout: Some("5.1(side)".to_string()),
+            sample_rate: Some("96000".to_string()),
+            ..mk_audio(2, "ac3", "eng")
+        });
+
+        let validation = validate_format(&metadata, &format);
+        assert!(!validation.is_valid());
+        assert!(validation.audio[0].components().all(|c| c.okay));
+        let surround = &validation.audio[1];
+        assert_eq!(
+            surround.channels.as_ref().unwrap().reason,
+            "audio 6 channels exceeds the limit of 2 channels"
+        );
+        assert_eq!(
+            surround.channel_layout.as_ref().unwrap().rule,
+            RuleMatch::AllowMiss
+        );
+        assert!(!surround.sample_rate.as_ref().unwrap().okay);
+    }
+
+    #[test]
+    fn format_validation_add_compat_needs_one_compatible_audio_stream() {
+        let mut format = mk_spec_allow(vec!["aac", "ac3"], vec!["h264"], vec!["matroska"]);
+        format.audio_strategy = AudioStrategy::AddCompat;
+        let mut metadata = mk_metadata("matroska", "h264", "truehd");
+
+        let validation = validate_format(&metadata, &format);
+        assert!(!validation.is_valid());
+        let compatible = validation.compatible_audio.as_ref().unwrap();
+        assert_eq!(compatible.rule, RuleMatch::FallbackMissing);
+
+        metadata.audio.push(mk_audio(2, "aac", "eng"));
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.is_valid());
+        assert!(!validation.audio[0].codec.okay);
+        assert_eq!(
+            validation.compatible_audio.as_ref().unwrap().reason,
+            "audio stream 2 (aac) is compatible"
+        );
+    }
+
+    #[test]
+    fn format_validation_rejects_hdr_but_not_10_bit_sdr() {
+        let mut format = mk_spec_reject(vec![], vec![], vec![]);
+        format.hdr = Some(Formats::Reject(str_vec(vec!["hdr10", "dolby-vision"])));
+        let mut metadata = mk_metadata("matroska", "hevc", "aac");
+        metadata.video[0].pix_fmt = "yuv420p10le".to_string();
+
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.is_valid());
+
+        metadata.video[0].hdr = HdrFormat::Hdr10;
+        let validation = validate_format(&metadata, &format);
+        assert_eq!(
+            validation.video[0].hdr.as_ref().unwrap().reason,
+            "dynamic range hdr10 is rejected"
+        );
+        assert!(!validation.is_valid());
+    }
+
+    #[test]
+    fn format_validation_dolby_vision_profiles() {
+        let mut format = mk_spec_reject(vec![], vec![], vec![]);
+        format.dolby_vision_profile = Some(Formats::Reject(str_vec(vec!["5"])));
+        let mut metadata = mk_metadata("matroska", "hevc", "aac");
+
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.video[0].dolby_vision.is_none());
+
+        metadata.video[0].hdr = HdrFormat::DolbyVision;
+        metadata.video[0].dolby_vision = Some(DolbyVision {
+            profile: 5,
+            level: Some(6),
+            base_layer: None,
+        });
+        let validation = validate_format(&metadata, &format);
+        assert_eq!(
+            validation.video[0].dolby_vision.as_ref().unwrap().reason,
+            "Dolby Vision profile 5 is rejected"
+        );
+        assert!(!validation.video[0].dynamic_range_okay());
+    }
+
+    #[test]
+    fn format_validation_codec_tags() {
+        let mut format = mk_spec_reject(vec![], vec![], vec![]);
+        format.video_codec_tag = Some(Formats::Allow(str_vec(vec!["hvc1", "avc1"])));
+        let mut metadata = mk_metadata("mov", "hevc", "aac");
+        metadata.video[0].codec_tag = Some("hev1".to_string());
+
+        let validation = validate_format(&metadata, &format);
+        assert!(!validation.is_valid());
+        // a bad tag alone doesn't need the picture reencoded
+        assert!(validation.video[0].picture_components().all(|c| c.okay));
+
+        // Matroska doesn't have codec tags
+        metadata.container = "matroska".to_string();
+        metadata.video[0].codec_tag = None;
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.video[0].codec_tag.is_none());
+        assert!(validation.is_valid());
+    }
+
+    #[test]
+    fn format_validation_rejects_interlaced_video() {
+        let mut format = mk_spec_reject(vec![], vec![], vec![]);
+        format.interlaced = Some(false);
+        let mut metadata = mk_metadata("matroska", "mpeg2video", "ac3");
+
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.is_valid());
+
+        metadata.video[0].field_order = Some("tt".to_string());
+        let validation = validate_format(&metadata, &format);
+        let interlaced = validation.video[0].interlaced.as_ref().unwrap();
+        assert_eq!(interlaced.rule, RuleMatch::RejectHit);
+        assert_eq!(interlaced.reason, "video is interlaced (field order tt)");
+
+        // interlaced video is accepted unless the target says otherwise
+        format.interlaced = None;
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.is_valid());
+    }
+
+    #[test]
+    fn format_validation_languages_and_default_audio() {
+        let mut format = mk_spec_reject(vec![], vec![], vec![]);
+        format.languages = Some(LanguageSpec {
+            audio: str_vec(vec!["eng", "spa"]),
+            default_audio: DefaultAudio::Preferred,
+            audio_selection: None,
+            flag_forced_subtitles: false,
+        });
+        let mut metadata = mk_metadata("matroska", "h264", "aac");
+        metadata.audio[0].language = Some("jpn".to_string());
+        metadata.audio[0].disposition.default = true;
+
+        let validation = validate_format(&metadata, &format);
+        let language = validation.audio_language.as_ref().unwrap();
+        assert_eq!(language.rule, RuleMatch::AllowMiss);
+        // no fix can add a missing language
+        assert_eq!(validation.unfixable().count(), 1);
+        assert_eq!(validation.fixable().count(), 0);
+        assert_eq!(
+            language.reason,
+            "no audio stream is in a required language (eng, spa)"
+        );
+        assert!(validation.default_audio.is_none());
+
+        metadata.audio.push(mk_audio(2, "aac", "spa"));
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.audio_language.as_ref().unwrap().okay);
+        // a wrong default is fixed by setting dispositions
+        assert!(!validation.is_valid());
+        assert_eq!(validation.unfixable().count(), 0);
+        assert_eq!(validation.fixable().count(), 1);
+        assert_eq!(
+            validation.default_audio.as_ref().unwrap().reason,
+            "default audio stream 1 (jpn) is not in the preferred language spa"
+        );
+
+        metadata.audio[0].disposition.default = false;
+        metadata.audio[1].disposition.default = true;
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.is_valid());
+
+        // the default stream has to come first as well
+        format.languages.as_mut().unwrap().default_audio = DefaultAudio::PreferredFirst;
+        let validation = validate_format(&metadata, &format);
+        let default_audio = validation.default_audio.as_ref().unwrap();
+        assert_eq!(default_audio.rule, RuleMatch::FlagMissing);
+        assert_eq!(
+            default_audio.reason,
+            "default audio stream 2 (spa) is not the first audio stream"
+        );
+    }
+
+    #[test]
+    fn format_validation_untagged_audio_language_is_unknown() {
+        let mut format = mk_spec_reject(vec![], vec![], vec![]);
+        format.languages = Some(LanguageSpec {
+            audio: str_vec(vec!["eng"]),
+            default_audio: DefaultAudio::Any,
+            audio_selection: None,
+            flag_forced_subtitles: false,
+        });
+        let mut metadata = mk_metadata("matroska", "h264", "aac");
+        metadata.audio[0].language = None;
+
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.is_valid());
+        assert_eq!(
+            validation.audio_language.as_ref().unwrap().rule,
+            RuleMatch::Unknown
+        );
+    }
+
+    #[test]
+    fn format_validation_forced_subtitles_are_flagged() {
+        let mut format = mk_spec_reject(vec![], vec![], vec![]);
+        format.languages = Some(LanguageSpec {
+            audio: str_vec(vec!["eng"]),
+            default_audio: DefaultAudio::Any,
+            audio_selection: None,
+            flag_forced_subtitles: true,
+        });
+        let mut metadata = mk_metadata("matroska", "h264", "aac");
+        for (index, title) in [(2, "English"), (3, "English (Forced)")] {
+            metadata.subtitle.push(SubtitleMetadata {
+                index,
+                codec: "subrip".to_string(),
+                language: Some("eng".to_string()),
+                title: Some(title.to_string()),
+                disposition: Disposition::default(),
+            });
+        }
+
+        let validation = validate_format(&metadata, &format);
+        assert!(!validation.is_valid());
+        assert!(validation.subtitle[0].forced.is_none());
+        assert_eq!(
+            validation.subtitle[1].forced.as_ref().unwrap().reason,
+            "subtitle stream 3 is titled as forced but not flagged forced"
+        );
+
+        metadata.subtitle[1].disposition.forced = true;
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.is_valid());
+    }
+
+    #[test]
+    fn format_validation_only_checks_selected_audio() {
+        let mut format = mk_spec_allow(vec!["aac"], vec!["h264"], vec!["matroska"]);
+        format.languages = Some(LanguageSpec {
+            audio: str_vec(vec!["eng"]),
+            default_audio: DefaultAudio::Any,
+            audio_selection: Some(AudioSelection::PreferredAndOriginal),
+            flag_forced_subtitles: false,
+        });
+        let mut metadata = mk_metadata("matroska", "h264", "truehd");
+        metadata.audio[0].language = Some("jpn".to_string());
+        for (index, codec, language) in [(2, "aac", "eng"), (3, "dts", "fre")] {
+            metadata.audio.push(mk_audio(index, codec, language));
+        }
+
+        let validation = validate_format(&metadata, &format);
+        assert!(validation.is_valid());
+        let selection = validation.audio.iter().map(|a| a.selection).collect_vec();
+        assert_eq!(
+            selection,
+            [Selection::Kept, Selection::Checked, Selection::Dropped]
+        );
+
+        // without audio in a preferred language every stream counts
+        metadata.audio.remove(1);
+        let validation = validate_format(&metadata, &format);
+        assert!(validation
+            .audio
+            .iter()
+            .all(|a| a.selection == Selection::Checked));
+        assert!(!validation.is_valid());
+    }
 }